@@ -1,14 +1,27 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{RwLock};
 use utils::*;
 use std::sync::{Arc};
 
 pub type Amount = i64;
 
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Balance {
+    // Free to be used by new orders
+    pub available: Amount,
+    // Reserved by resting orders
+    pub held: Amount,
+}
+
+// Owned by the engine once it starts. Clones share all of the state, so
+// others may read balances and fee tiers as the engine keeps them.
 #[derive(Clone)]
 pub struct Balances {
-    pub balances: Arc<RwLock<HashMap<(UserId, AssetId), Amount>>>,
+    pub balances: Arc<RwLock<HashMap<(UserId, AssetId), Balance>>>,
     config: Config,
+    // Quote volume traded per day, oldest first, for fee tiers
+    volumes: Arc<RwLock<HashMap<(UserId, MarketId), VecDeque<(u64, Amount)>>>>,
+    pinned_fee_tiers: Arc<RwLock<HashMap<(UserId, MarketId), u32>>>,
 }
 
 // TODO: Could lock per user
@@ -17,72 +30,222 @@ impl Balances {
         Balances {
             balances: Arc::new(RwLock::new(HashMap::new())),
             config: config,
+            volumes: Arc::new(RwLock::new(HashMap::new())),
+            pinned_fee_tiers: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    fn get_balance_from_unlocked(map: &HashMap<(UserId, AssetId), Amount>, user_id: UserId, asset_id: AssetId) -> Amount {
+    fn get_balance_from_unlocked(map: &HashMap<(UserId, AssetId), Balance>, user_id: UserId, asset_id: AssetId) -> Balance {
         match map.get(&(user_id, asset_id)) {
-            None => 0,
+            None => Balance::default(),
             Some(balance) => *balance
         }
     }
 
+    fn update_from_unlocked<F: FnOnce(&mut Balance)>(map: &mut HashMap<(UserId, AssetId), Balance>, user_id: UserId, asset_id: AssetId, f: F) -> Balance {
+        let balance = map.entry((user_id, asset_id)).or_insert(Balance::default());
+        f(balance);
+        *balance
+    }
+
     pub fn adjust_balance(&mut self, user_id: UserId, asset_id: AssetId, change: Amount) -> Amount {
         Balances::adjust_balance_from_unlocked(&mut self.balances.write().unwrap(), user_id, asset_id, change)
     }
 
-    fn adjust_balance_from_unlocked(map: &mut HashMap<(UserId, AssetId), Amount>, user_id: UserId, asset_id: AssetId, change: i64) -> Amount {
-        let prev_balance = Balances::get_balance_from_unlocked(map, user_id, asset_id);
-        let next_balance = prev_balance + change;
-        map.insert((user_id, asset_id), next_balance as Amount);
-        next_balance
+    // Changes the available amount
+    fn adjust_balance_from_unlocked(map: &mut HashMap<(UserId, AssetId), Balance>, user_id: UserId, asset_id: AssetId, change: i64) -> Amount {
+        Balances::update_from_unlocked(map, user_id, asset_id, |x| x.available += change).available
+    }
+
+    // Moves an amount from available to held, or back when negative
+    fn hold_from_unlocked(map: &mut HashMap<(UserId, AssetId), Balance>, user_id: UserId, asset_id: AssetId, amount: Amount) {
+        Balances::update_from_unlocked(map, user_id, asset_id, |x| {
+            x.available -= amount;
+            x.held += amount;
+        });
     }
 
+    // Removes an amount that has left the account from held
+    fn consume_hold_from_unlocked(map: &mut HashMap<(UserId, AssetId), Balance>, user_id: UserId, asset_id: AssetId, amount: Amount) {
+        Balances::update_from_unlocked(map, user_id, asset_id, |x| x.held -= amount);
+    }
+
+    // The available amount
     pub fn get_balance(&self, user_id: UserId, asset_id: AssetId) -> Amount {
+        self.get(user_id, asset_id).available
+    }
+
+    pub fn get_held(&self, user_id: UserId, asset_id: AssetId) -> Amount {
+        self.get(user_id, asset_id).held
+    }
+
+    pub fn get(&self, user_id: UserId, asset_id: AssetId) -> Balance {
         let balances = self.balances.read().unwrap();
         Balances::get_balance_from_unlocked(&*balances, user_id, asset_id)
     }
 
     // TODO: Move to Order impl
-    fn get_requirement_for_order(&self, order: &Order) -> (AssetId, Amount) {
+    pub fn get_requirement_for_order(&self, order: &Order) -> (AssetId, Amount) {
         let ref market = self.config.markets[&order.market_id];
-        match order.side {
-            OrderSide::Buy => (market.quote_asset_id, (order.remaining * order.price) as Amount),
-            OrderSide::Sell => (market.base_asset_id, order.remaining as Amount),
+        match (order.side, order.order_type) {
+            (OrderSide::Buy, OrderType::Limit) => (market.quote_asset_id, (order.remaining * order.price) as Amount),
+            // The final price of a market buy is unknown, so its funds are held
+            (OrderSide::Buy, OrderType::Market) => (market.quote_asset_id, order.funds.unwrap_or(0) as Amount),
+            (OrderSide::Sell, _) => (market.base_asset_id, order.remaining as Amount),
         }
     }
 
-    pub fn settle(&mut self, trade: &Trade) {
-        let market = &self.config.markets[&trade.market_id];
-        let total = trade.price * trade.size;
+    // Quote volume traded in the market over the fee tier window ending at timestamp
+    pub fn get_trailing_volume(&self, user_id: UserId, market_id: MarketId, timestamp: u64) -> Amount {
+        let day = timestamp / 86400;
+
+        let volumes = self.volumes.read().unwrap();
+
+        match volumes.get(&(user_id, market_id)) {
+            None => 0,
+            Some(days) => {
+                days.iter()
+                    .filter(|x| x.0 + FEE_TIER_WINDOW_DAYS > day)
+                    .fold(0, |acc, x| acc + x.1)
+            }
+        }
+    }
+
+    fn record_volume(&mut self, user_id: UserId, market_id: MarketId, timestamp: u64, amount: Amount) {
+        let day = timestamp / 86400;
+        let mut volumes = self.volumes.write().unwrap();
+        let days = volumes.entry((user_id, market_id)).or_insert(VecDeque::new());
+
+        while days.front().map_or(false, |x| x.0 + FEE_TIER_WINDOW_DAYS <= day) {
+            days.pop_front();
+        }
+
+        let is_same_day = days.back().map_or(false, |x| x.0 == day);
+
+        if is_same_day {
+            days.back_mut().unwrap().1 += amount;
+        } else {
+            days.push_back((day, amount));
+        }
+    }
+
+    pub fn pin_fee_tier(&mut self, user_id: UserId, market_id: MarketId, tier: Option<u32>) {
+        let mut pinned_fee_tiers = self.pinned_fee_tiers.write().unwrap();
+
+        match tier {
+            Some(tier) => { pinned_fee_tiers.insert((user_id, market_id), tier); },
+            None => { pinned_fee_tiers.remove(&(user_id, market_id)); },
+        }
+    }
+
+    // The maker and taker rates the user pays in the market
+    pub fn get_fee_rates(&self, user_id: UserId, market_id: MarketId, timestamp: u64) -> (FeeRate, FeeRate) {
+        let market = &self.config.markets[&market_id];
+
+        let pinned = self.pinned_fee_tiers.read().unwrap().get(&(user_id, market_id)).cloned();
+
+        let tier = match pinned {
+            Some(tier) => market.fee_tiers.get(tier as usize),
+            None => {
+                let volume = self.get_trailing_volume(user_id, market_id, timestamp);
+                market.fee_tiers.iter().rev().find(|x| x.min_volume <= volume)
+            },
+        };
+
+        match tier {
+            Some(tier) => (tier.maker_fee, tier.taker_fee),
+            None => (market.maker_fee, market.taker_fee),
+        }
+    }
+
+    // Pays out a trade from the funds both sides have on hold. Each side is
+    // charged its fee out of the asset it receives at the rate for its volume
+    // before the trade, and the fees are recorded on the trade. Auction trades
+    // record the buyer as the taker.
+    pub fn settle(&mut self, trade: &mut Trade, timestamp: u64) {
+        let total = (trade.price * trade.size) as Amount;
+        let size = trade.size as Amount;
 
         let (buy_user_id, sell_user_id) = match trade.side {
             OrderSide::Buy => (trade.maker_user_id, trade.taker_user_id),
             OrderSide::Sell => (trade.taker_user_id, trade.maker_user_id),
         };
 
+        let (buy_maker_fee, buy_taker_fee) = self.get_fee_rates(buy_user_id, trade.market_id, timestamp);
+        let (sell_maker_fee, sell_taker_fee) = self.get_fee_rates(sell_user_id, trade.market_id, timestamp);
+
+        let (buy_fee, sell_fee) = match trade.side {
+            _ if trade.auction => (fee_for(size, buy_taker_fee), fee_for(total, sell_taker_fee)),
+            OrderSide::Buy => (fee_for(size, buy_maker_fee), fee_for(total, sell_taker_fee)),
+            OrderSide::Sell => (fee_for(size, buy_taker_fee), fee_for(total, sell_maker_fee)),
+        };
+
+        self.record_volume(buy_user_id, trade.market_id, timestamp, total);
+        self.record_volume(sell_user_id, trade.market_id, timestamp, total);
+
+        let market = &self.config.markets[&trade.market_id];
         let mut balances = self.balances.write().unwrap();
 
-        Balances::adjust_balance_from_unlocked(&mut balances, buy_user_id, market.base_asset_id, trade.size as Amount);
-        Balances::adjust_balance_from_unlocked(&mut balances, sell_user_id, market.quote_asset_id, total as Amount);
+        Balances::consume_hold_from_unlocked(&mut balances, buy_user_id, market.quote_asset_id, total);
+        Balances::adjust_balance_from_unlocked(&mut balances, buy_user_id, market.base_asset_id, size - buy_fee);
+        Balances::consume_hold_from_unlocked(&mut balances, sell_user_id, market.base_asset_id, size);
+        Balances::adjust_balance_from_unlocked(&mut balances, sell_user_id, market.quote_asset_id, total - sell_fee);
+
+        Balances::adjust_balance_from_unlocked(&mut balances, self.config.fee_user_id, market.base_asset_id, buy_fee);
+        Balances::adjust_balance_from_unlocked(&mut balances, self.config.fee_user_id, market.quote_asset_id, sell_fee);
+
+        // A buying taker reserved at its own limit but paid the maker's lower
+        // price. Release the difference so the hold matches what is left.
+        if trade.side == OrderSide::Sell && trade.taker_price > trade.price {
+            let improvement = ((trade.taker_price - trade.price) * trade.size) as Amount;
+            Balances::hold_from_unlocked(&mut balances, buy_user_id, market.quote_asset_id, -improvement);
+        }
+
+        match trade.side {
+            OrderSide::Buy => {
+                trade.maker_fee = buy_fee;
+                trade.taker_fee = sell_fee;
+            },
+            OrderSide::Sell => {
+                trade.maker_fee = sell_fee;
+                trade.taker_fee = buy_fee;
+            },
+        }
     }
 
     pub fn user_can_afford_order(&self, order: &Order) -> bool {
         let balances = self.balances.read().unwrap();
         let (asset_id, balance_requirement) = self.get_requirement_for_order(order);
-        Balances::get_balance_from_unlocked(&balances, order.user_id, asset_id) >= balance_requirement
+        Balances::get_balance_from_unlocked(&balances, order.user_id, asset_id).available >= balance_requirement
+    }
+
+    // Whether the funds held for the order and what is available cover the
+    // order as amended
+    pub fn user_can_afford_amendment(&self, order: &Order, amended: &Order) -> bool {
+        let balances = self.balances.read().unwrap();
+        let (asset_id, held) = self.get_requirement_for_order(order);
+        let (_, balance_requirement) = self.get_requirement_for_order(amended);
+        Balances::get_balance_from_unlocked(&balances, order.user_id, asset_id).available + held >= balance_requirement
+    }
+
+    // Moves an amount from available to held, or back when negative
+    pub fn hold(&mut self, user_id: UserId, asset_id: AssetId, amount: Amount) {
+        let mut balances = self.balances.write().unwrap();
+        Balances::hold_from_unlocked(&mut balances, user_id, asset_id, amount);
     }
 
+    // Puts the funds the order needs on hold
     pub fn debit_for_order(&mut self, order: &Order) {
         let mut balances = self.balances.write().unwrap();
         let (asset_id, balance_requirement) = self.get_requirement_for_order(order);
-        Balances::adjust_balance_from_unlocked(&mut balances, order.user_id, asset_id, -balance_requirement);
+        Balances::hold_from_unlocked(&mut balances, order.user_id, asset_id, balance_requirement);
     }
 
+    // Releases the funds held for the remainder of the order
     pub fn credit_for_canceled_order(&mut self, order: &Order) {
         let mut balances = self.balances.write().unwrap();
         let (asset_id, balance_requirement) = self.get_requirement_for_order(order);
-        Balances::adjust_balance_from_unlocked(&mut balances, order.user_id, asset_id, balance_requirement);
+        Balances::hold_from_unlocked(&mut balances, order.user_id, asset_id, -balance_requirement);
     }
 }
 
@@ -112,39 +275,39 @@ mod tests {
     fn it_debits_correct_amount_for_bid() {
         let mut balances = Balances::new(Config::hardcoded());
 
-        {
-            let mut unlocked = balances.balances.write().unwrap();
-            unlocked.insert((1, 2), 1000);
-        }
+        balances.adjust_balance(1, 2, 1000);
 
         let order = Order::new(1, 1, 1, OrderSide::Buy, 10, 20);
         balances.debit_for_order(&order);
 
-        {
-            let unlocked = balances.balances.read().unwrap();
-            assert_eq!(unlocked[&(1, 2)], 1000 - 10 * 20);
-        }
+        assert_eq!(balances.get(1, 2), Balance { available: 1000 - 10 * 20, held: 10 * 20 });
     }
 
     #[test]
     fn it_debits_correct_amount_for_ask() {
         let mut balances = Balances::new(Config::hardcoded());
 
-        {
-            let mut unlocked =  balances.balances.write().unwrap();
-            unlocked.insert((1, 1), 1000);
-        }
+        balances.adjust_balance(1, 1, 1000);
 
         let order = Order::new(1, 1, 1, OrderSide::Sell, 10, 20);
         balances.debit_for_order(&order);
 
-        {
-            let unlocked = balances.balances.read().unwrap();
-            assert_eq!(unlocked[&(1, 1)], 1000 - 20);
-        }
+        assert_eq!(balances.get(1, 1), Balance { available: 1000 - 20, held: 20 });
     }
 
-    // TODO: Giving back remainder when an order is canceled
+    #[test]
+    fn it_releases_hold_when_order_is_canceled() {
+        let mut balances = Balances::new(Config::hardcoded());
+        balances.adjust_balance(1, 2, 1000);
+
+        let mut order = Order::new(1, 1, 1, OrderSide::Buy, 10, 20);
+        balances.debit_for_order(&order);
+
+        order.remaining = 5;
+        balances.credit_for_canceled_order(&order);
+
+        assert_eq!(balances.get(1, 2), Balance { available: 1000 - 10 * 20 + 10 * 5, held: 10 * 15 });
+    }
 
     #[test]
     fn it_settles_correctly_for_annihilation() {
@@ -157,11 +320,13 @@ mod tests {
 
         {
             let mut unlocked =  balances.balances.write().unwrap();
-            unlocked.insert((buy_user_id, base_asset_id), 25);
-            unlocked.insert((sell_user_id, quote_asset_id), 30);
+            unlocked.insert((buy_user_id, base_asset_id), Balance { available: 25, held: 0 });
+            unlocked.insert((buy_user_id, quote_asset_id), Balance { available: 0, held: 500 * 1000 });
+            unlocked.insert((sell_user_id, base_asset_id), Balance { available: 0, held: 500 });
+            unlocked.insert((sell_user_id, quote_asset_id), Balance { available: 30, held: 0 });
         }
 
-        let trade = Trade {
+        let mut trade = Trade {
             price: 1000,
             size: 500,
             maker_order_id: 15,
@@ -170,14 +335,137 @@ mod tests {
             taker_user_id: sell_user_id,
             side: OrderSide::Buy,
             market_id: 1,
+            taker_price: 1000,
+            maker_fee: 0,
+            taker_fee: 0,
+            auction: false,
         };
 
-        balances.settle(&trade);
+        balances.settle(&mut trade, 0);
 
         {
             let unlocked = balances.balances.read().unwrap();
-            assert_eq!(unlocked[&(buy_user_id, base_asset_id)], 25 + 500);
-            assert_eq!(unlocked[&(sell_user_id, quote_asset_id)], 30 + 500 * 1000);
+            assert_eq!(unlocked[&(buy_user_id, base_asset_id)].available, 25 + 500);
+            assert_eq!(unlocked[&(buy_user_id, quote_asset_id)].held, 0);
+            assert_eq!(unlocked[&(sell_user_id, base_asset_id)].held, 0);
+            assert_eq!(unlocked[&(sell_user_id, quote_asset_id)].available, 30 + 500 * 1000);
+        }
+    }
+
+    #[test]
+    fn it_refunds_price_improvement_to_buying_taker() {
+        let mut balances = Balances::new(Config::hardcoded());
+        const buy_user_id: UserId = 101;
+        const sell_user_id: UserId = 102;
+        const quote_asset_id: AssetId = 2;
+
+        balances.adjust_balance(buy_user_id, quote_asset_id, 10000);
+        balances.adjust_balance(sell_user_id, 1, 10);
+
+        let maker = Order::new(1, sell_user_id, 1, OrderSide::Sell, 90, 10);
+        let mut taker = Order::new(2, buy_user_id, 1, OrderSide::Buy, 100, 30);
+
+        balances.debit_for_order(&maker);
+        balances.debit_for_order(&taker);
+
+        let mut trade = Trade::new(&maker, &taker);
+        balances.settle(&mut trade, 0);
+
+        // 10 @ 90 was paid, the remaining 20 are still reserved at 100
+        taker.remaining -= trade.size;
+        assert_eq!(balances.get(buy_user_id, quote_asset_id), Balance { available: 10000 - 10 * 90 - 20 * 100, held: 20 * 100 });
+
+        balances.credit_for_canceled_order(&taker);
+        assert_eq!(balances.get(buy_user_id, quote_asset_id), Balance { available: 10000 - 10 * 90, held: 0 });
+    }
+
+    #[test]
+    fn it_charges_fees_into_fee_account() {
+        let mut config = Config::hardcoded();
+        config.fee_user_id = 99;
+
+        {
+            let market = config.markets.get_mut(&1).unwrap();
+            market.maker_fee = -1000;
+            market.taker_fee = 2500;
         }
+
+        let mut balances = Balances::new(config);
+        const buy_user_id: UserId = 101;
+        const sell_user_id: UserId = 102;
+
+        balances.adjust_balance(buy_user_id, 2, 1000000);
+        balances.adjust_balance(sell_user_id, 1, 10000);
+
+        let maker = Order::new(1, buy_user_id, 1, OrderSide::Buy, 100, 10000);
+        let taker = Order::new(2, sell_user_id, 1, OrderSide::Sell, 100, 10000);
+
+        balances.debit_for_order(&maker);
+        balances.debit_for_order(&taker);
+
+        let mut trade = Trade::new(&maker, &taker);
+        balances.settle(&mut trade, 0);
+
+        // The maker buyer is rebated 0.1% of the base it received, the taker
+        // seller pays 0.25% of the quote it received
+        assert_eq!(trade.maker_fee, -10);
+        assert_eq!(trade.taker_fee, 2500);
+        assert_eq!(balances.get_balance(buy_user_id, 1), 10000 + 10);
+        assert_eq!(balances.get_balance(sell_user_id, 2), 1000000 - 2500);
+        assert_eq!(balances.get_balance(99, 1), -10);
+        assert_eq!(balances.get_balance(99, 2), 2500);
+    }
+
+    #[test]
+    fn it_charges_fee_tier_for_trailing_volume() {
+        let mut config = Config::hardcoded();
+
+        {
+            let market = config.markets.get_mut(&1).unwrap();
+            market.maker_fee = 1000;
+            market.taker_fee = 2000;
+            market.fee_tiers = vec![
+                FeeTier { min_volume: 1000, maker_fee: 500, taker_fee: 1500 },
+                FeeTier { min_volume: 5000, maker_fee: 0, taker_fee: 1000 },
+            ];
+        }
+
+        let mut balances = Balances::new(config);
+        const DAY: u64 = 86400;
+
+        assert_eq!(balances.get_fee_rates(1, 1, 0), (1000, 2000));
+
+        balances.record_volume(1, 1, 0, 600);
+        balances.record_volume(1, 1, DAY, 600);
+        assert_eq!(balances.get_trailing_volume(1, 1, DAY), 1200);
+        assert_eq!(balances.get_fee_rates(1, 1, DAY), (500, 1500));
+
+        // The first day falls out of the window
+        assert_eq!(balances.get_trailing_volume(1, 1, 30 * DAY), 600);
+        assert_eq!(balances.get_fee_rates(1, 1, 30 * DAY), (1000, 2000));
+
+        balances.pin_fee_tier(1, 1, Some(1));
+        assert_eq!(balances.get_fee_rates(1, 1, 30 * DAY), (0, 1000));
+
+        balances.pin_fee_tier(1, 1, None);
+        assert_eq!(balances.get_fee_rates(1, 1, 30 * DAY), (1000, 2000));
+    }
+
+    #[test]
+    fn it_shares_fee_tiers_between_clones() {
+        let mut config = Config::hardcoded();
+        config.markets.get_mut(&1).unwrap().fee_tiers = vec![
+            FeeTier { min_volume: 1000, maker_fee: 500, taker_fee: 1500 },
+        ];
+
+        let mut balances = Balances::new(config);
+        let other = balances.clone();
+
+        balances.record_volume(1, 1, 0, 1000);
+        assert_eq!(other.get_trailing_volume(1, 1, 0), 1000);
+        assert_eq!(other.get_fee_rates(1, 1, 0), (500, 1500));
+
+        balances.pin_fee_tier(2, 1, Some(0));
+        assert_eq!(other.get_fee_rates(2, 1, 0), (500, 1500));
     }
 }
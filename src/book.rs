@@ -1,133 +1,1186 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::collections::{btree_map, vec_deque};
+use std::ops::Index;
 use utils::*;
 
+// Orders resting at a single price, oldest first. Removed orders are left in
+// the queue and skipped once they reach the front, so cancels never shift it.
+struct PriceLevel {
+    queue: VecDeque<(OrderId, u64)>,
+    count: usize,
+    // The displayed size, which leaves out what icebergs hide
+    size: OrderSize,
+}
+
+// A resting order and the ticket of its entry in the price level queue
+struct RestingOrder {
+    order: Order,
+    ticket: u64,
+    // What is left of the slice shown in the book
+    displayed: OrderSize,
+}
+
+// One side of the book. Levels are keyed so that iterating the map visits the
+// best price first on either side.
+pub struct BookSide {
+    side: OrderSide,
+    levels: BTreeMap<u64, PriceLevel>,
+    orders: HashMap<OrderId, RestingOrder>,
+    next_ticket: u64,
+}
+
+impl BookSide {
+    pub fn new(side: OrderSide) -> BookSide {
+        BookSide {
+            side: side,
+            levels: BTreeMap::new(),
+            orders: HashMap::new(),
+            next_ticket: 0,
+        }
+    }
+
+    // Bids are keyed by the inverted price so the highest bid sorts first
+    fn level_key(&self, price: OrderPrice) -> u64 {
+        match self.side {
+            OrderSide::Buy => !price,
+            OrderSide::Sell => price,
+        }
+    }
+
+    fn key_price(&self, key: u64) -> OrderPrice {
+        match self.side {
+            OrderSide::Buy => !key,
+            OrderSide::Sell => key,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    pub fn get(&self, order_id: OrderId) -> Option<&Order> {
+        self.orders.get(&order_id).map(|x| &x.order)
+    }
+
+    // The order as shown in the book, with its displayed size as remaining
+    pub fn visible(&self, order_id: OrderId) -> Option<Order> {
+        self.orders.get(&order_id).map(|x| Order { remaining: x.displayed, ..x.order })
+    }
+
+    // Displayed size per price for up to the given number of levels, best first
+    pub fn depth(&self, max_levels: usize) -> Vec<(OrderPrice, OrderSize)> {
+        self.levels.iter().take(max_levels).map(|(key, level)| (self.key_price(*key), level.size)).collect()
+    }
+
+    pub fn best_price(&self) -> Option<OrderPrice> {
+        self.levels.keys().next().map(|key| self.key_price(*key))
+    }
+
+    pub fn first(&self) -> Option<&Order> {
+        self.iter().next()
+    }
+
+    // Iterates resting orders from best to worst price, oldest first within a price
+    pub fn iter(&self) -> Iter {
+        Iter {
+            orders: &self.orders,
+            levels: self.levels.values(),
+            queue: None,
+        }
+    }
+
+    // Adds the order at the back of its price level
+    pub fn insert(&mut self, order: Order) {
+        let key = self.level_key(order.price);
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+
+        let displayed = order.display_size.map_or(order.remaining, |x| cmp::min(x, order.remaining));
+
+        {
+            let level = self.levels.entry(key).or_insert_with(|| PriceLevel {
+                queue: VecDeque::new(),
+                count: 0,
+                size: 0,
+            });
+
+            level.queue.push_back((order.id, ticket));
+            level.count += 1;
+            level.size += displayed;
+        }
+
+        self.orders.insert(order.id, RestingOrder {
+            order: order,
+            ticket: ticket,
+            displayed: displayed,
+        });
+    }
+
+    // Replaces a resting order with a smaller one at the same price, keeping
+    // its place in the level
+    pub fn reduce(&mut self, order: Order) {
+        let hidden = {
+            let resting = self.orders.get_mut(&order.id).unwrap();
+            let displayed = cmp::min(resting.displayed, order.remaining);
+            let hidden = resting.displayed - displayed;
+
+            resting.order = order;
+            resting.displayed = displayed;
+            hidden
+        };
+
+        let key = self.level_key(order.price);
+        self.levels.get_mut(&key).unwrap().size -= hidden;
+    }
+
+    pub fn remove(&mut self, order_id: OrderId) -> Option<Order> {
+        let resting = match self.orders.remove(&order_id) {
+            None => return None,
+            Some(resting) => resting,
+        };
+
+        let key = self.level_key(resting.order.price);
+
+        let is_empty = {
+            let orders = &self.orders;
+            let level = self.levels.get_mut(&key).unwrap();
+
+            level.count -= 1;
+            level.size -= resting.displayed;
+
+            // Drop stale entries once they outnumber the live ones
+            if level.queue.len() > 2 * level.count + 32 {
+                level.queue.retain(|&(id, ticket)| {
+                    orders.get(&id).map_or(false, |x| x.ticket == ticket)
+                });
+            }
+
+            level.count == 0
+        };
+
+        if is_empty {
+            self.levels.remove(&key);
+        }
+
+        Some(resting.order)
+    }
+
+    // The orders at the best price, oldest first
+    fn best_level(&self) -> Vec<OrderId> {
+        let orders = &self.orders;
+
+        match self.levels.values().next() {
+            None => vec![],
+            Some(level) => {
+                level.queue.iter()
+                    .filter(|&&(id, ticket)| orders.get(&id).map_or(false, |x| x.ticket == ticket))
+                    .map(|&(id, _)| id)
+                    .collect()
+            }
+        }
+    }
+
+    // Fills size that may reach into what an iceberg hides, a slice at a time
+    fn fill_hidden(&mut self, order_id: OrderId, mut size: OrderSize) {
+        while size > 0 {
+            let filled = cmp::min(self.orders[&order_id].displayed, size);
+            self.fill(order_id, filled);
+            size -= filled;
+        }
+    }
+
+    // Reduces the remaining size of a resting order by no more than it
+    // displays, removing it once filled. An iceberg whose slice is consumed
+    // shows the next one at the back of the level.
+    fn fill(&mut self, order_id: OrderId, size: OrderSize) {
+        let (order, displayed) = {
+            let resting = self.orders.get_mut(&order_id).unwrap();
+            resting.order.remaining -= size;
+            resting.displayed -= size;
+            (resting.order, resting.displayed)
+        };
+
+        let key = self.level_key(order.price);
+        self.levels.get_mut(&key).unwrap().size -= size;
+
+        if order.remaining == 0 {
+            self.remove(order_id);
+        } else if displayed == 0 {
+            self.remove(order_id);
+            self.insert(order);
+        }
+    }
+}
+
+impl Index<usize> for BookSide {
+    type Output = Order;
+
+    fn index(&self, index: usize) -> &Order {
+        self.iter().nth(index).expect("order index out of bounds")
+    }
+}
+
+pub struct Iter<'a> {
+    orders: &'a HashMap<OrderId, RestingOrder>,
+    levels: btree_map::Values<'a, u64, PriceLevel>,
+    queue: Option<vec_deque::Iter<'a, (OrderId, u64)>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Order;
+
+    fn next(&mut self) -> Option<&'a Order> {
+        let orders = self.orders;
+
+        loop {
+            if let Some(ref mut queue) = self.queue {
+                for &(id, ticket) in queue {
+                    match orders.get(&id) {
+                        Some(resting) if resting.ticket == ticket => return Some(&resting.order),
+                        _ => {},
+                    }
+                }
+            }
+
+            match self.levels.next() {
+                None => return None,
+                Some(level) => self.queue = Some(level.queue.iter()),
+            }
+        }
+    }
+}
+
+// Decides how much of a taker's size each order resting at a price receives
+pub trait MatchingAlgorithm {
+    // Given the displayed sizes of the orders at the price, oldest first, and
+    // a size no larger than their total, returns the size allocated to each
+    fn allocate(&self, resting: &[OrderSize], size: OrderSize) -> Vec<OrderSize>;
+}
+
+pub struct Fifo;
+
+impl MatchingAlgorithm for Fifo {
+    fn allocate(&self, resting: &[OrderSize], mut size: OrderSize) -> Vec<OrderSize> {
+        resting.iter().map(|x| {
+            let allocation = cmp::min(*x, size);
+            size -= allocation;
+            allocation
+        }).collect()
+    }
+}
+
+impl MatchingAlgorithm for ProRata {
+    fn allocate(&self, resting: &[OrderSize], mut size: OrderSize) -> Vec<OrderSize> {
+        let mut allocations = vec![0; resting.len()];
+
+        if self.top_order && !resting.is_empty() {
+            allocations[0] = cmp::min(resting[0], size);
+            size -= allocations[0];
+        }
+
+        let skip = if self.top_order { 1 } else { 0 };
+        let total: OrderSize = resting.iter().skip(skip).sum();
+
+        if total > 0 {
+            for (i, x) in resting.iter().enumerate().skip(skip) {
+                // Shifting both sides of the ratio keeps huge levels from
+                // overflowing at the cost of some precision
+                let (mut numerator, mut denominator) = (*x, total);
+
+                while size.checked_mul(numerator).is_none() {
+                    numerator >>= 1;
+                    denominator >>= 1;
+                }
+
+                let allocation = size * numerator / denominator;
+
+                if allocation >= self.min_allocation {
+                    allocations[i] = allocation;
+                }
+            }
+
+            size -= allocations.iter().skip(skip).sum::<OrderSize>();
+        }
+
+        // What rounding left over is filled in time priority
+        for (i, x) in resting.iter().enumerate() {
+            let allocation = cmp::min(*x - allocations[i], size);
+            allocations[i] += allocation;
+            size -= allocation;
+        }
+
+        allocations
+    }
+}
+
+impl MatchingAlgorithm for Matching {
+    fn allocate(&self, resting: &[OrderSize], size: OrderSize) -> Vec<OrderSize> {
+        match *self {
+            Matching::Fifo => Fifo.allocate(resting, size),
+            Matching::ProRata(ref pro_rata) => pro_rata.allocate(resting, size),
+        }
+    }
+}
+
+// The outcome of executing an order against the book
+pub struct Execution {
+    // The order after matching
+    pub order: Order,
+    pub trades: Vec<Trade>,
+    // Whether the remainder of the order now rests in the book
+    pub resting: bool,
+    // Orders reduced by self trade prevention, each with the size that was
+    // canceled as its remaining size, or the funds for a market buy given
+    // only funds
+    pub canceled: Vec<Order>,
+}
+
 // A market is a collection of bids (buy orders) and asks (sell orders)
 pub struct Book {
-    pub bids: Vec<Order>,
-    pub asks: Vec<Order>,
+    pub bids: BookSide,
+    pub asks: BookSide,
+    // Good till date orders by expiry. Entries are not removed when the order
+    // fills or is canceled and are skipped once they come due instead.
+    expiries: BTreeSet<(u64, OrderId)>,
+    // The price of the most recent trade, which stop orders trigger on
+    last_price: Option<OrderPrice>,
+    // Orders rest without matching until the auction uncrosses
+    auction: bool,
+    // Stop orders waiting to trigger and their keys below
+    stops: HashMap<OrderId, (Order, u64)>,
+    // Keyed by stop price and placement, in the order they trigger in. Sell
+    // stops use the inverted price so that the highest comes first.
+    buy_stops: BTreeMap<(u64, u64), OrderId>,
+    sell_stops: BTreeMap<(u64, u64), OrderId>,
+    next_stop_ticket: u64,
+    // How takers are shared among the orders at each price
+    matching: Matching,
+    // Resting pegged orders. Entries are left behind when the order fills or
+    // is canceled and are dropped when next repriced.
+    pegged: BTreeSet<OrderId>,
+    // Sliding post only orders move to a price on the tick
+    tick_size: OrderPrice,
 }
 
 impl Book {
     pub fn new() -> Book {
+        Book::with_rules(1, Matching::Fifo)
+    }
+
+    pub fn with_rules(tick_size: OrderPrice, matching: Matching) -> Book {
         Book {
-            bids: vec![],
-            asks: vec![],
+            bids: BookSide::new(OrderSide::Buy),
+            asks: BookSide::new(OrderSide::Sell),
+            expiries: BTreeSet::new(),
+            last_price: None,
+            auction: false,
+            stops: HashMap::new(),
+            buy_stops: BTreeMap::new(),
+            sell_stops: BTreeMap::new(),
+            next_stop_ticket: 0,
+            matching: matching,
+            pegged: BTreeSet::new(),
+            tick_size: tick_size,
         }
     }
 
     fn match_orders(maker: &Order, taker: &Order) -> Option<Trade> {
-        // Make sure the opposite order offers equal or better price than requested
-        if taker.side == OrderSide::Buy && taker.price < maker.price {
-            None
-        } else if taker.side == OrderSide::Sell && taker.price > maker.price {
+        // Make sure the opposite order offers equal or better price than
+        // requested. Market orders take any price.
+        if taker.order_type == OrderType::Limit {
+            if taker.side == OrderSide::Buy && taker.price < maker.price {
+                return None;
+            } else if taker.side == OrderSide::Sell && taker.price > maker.price {
+                return None;
+            }
+        }
+
+        let mut trade = Trade::new(maker, taker);
+
+        if !taker.is_size_limited() {
+            trade.size = maker.remaining;
+        }
+
+        // Only take as much as the remaining funds pay for at the maker's price
+        if let Some(funds) = taker.funds {
+            trade.size = cmp::min(trade.size, funds / maker.price);
+        }
+
+        if trade.size == 0 {
             None
         } else {
-            Some(Trade::new(maker, taker))
+            Some(trade)
+        }
+    }
+
+    // Looks up a resting order or a stop order waiting to trigger
+    pub fn get_order(&self, order_id: OrderId) -> Option<&Order> {
+        self.bids.get(order_id)
+            .or_else(|| self.asks.get(order_id))
+            .or_else(|| self.stops.get(&order_id).map(|x| &x.0))
+    }
+
+    pub fn best_bid(&self) -> Option<OrderPrice> {
+        self.bids.best_price()
+    }
+
+    pub fn best_ask(&self) -> Option<OrderPrice> {
+        self.asks.best_price()
+    }
+
+    // Ids of the user's resting and stop orders, optionally on one side only,
+    // in ascending order
+    pub fn user_order_ids(&self, user_id: UserId, side: Option<OrderSide>) -> Vec<OrderId> {
+        let mut order_ids: Vec<OrderId> = self.bids.iter()
+            .chain(self.asks.iter())
+            .chain(self.stops.values().map(|x| &x.0))
+            .filter(|x| x.user_id == user_id && side.map_or(true, |side| x.side == side))
+            .map(|x| x.id)
+            .collect();
+
+        order_ids.sort();
+        order_ids
+    }
+
+    pub fn last_price(&self) -> Option<OrderPrice> {
+        self.last_price
+    }
+
+    pub fn mid_price(&self) -> Option<OrderPrice> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(best_bid), Some(best_ask)) => Some(best_bid / 2 + best_ask / 2 + (best_bid % 2 + best_ask % 2) / 2),
+            _ => None,
+        }
+    }
+
+    pub fn reference_price(&self, reference: PriceReference) -> Option<OrderPrice> {
+        match reference {
+            PriceReference::LastTrade => self.last_price.or_else(|| self.mid_price()),
+            PriceReference::Mid => self.mid_price(),
         }
     }
 
     pub fn cancel_order(&mut self, order_id: OrderId) -> Result<Order, ()> {
-        // TODO: Figure out how to keep orders indexed
-        match self.bids.iter().position(|x| x.id == order_id) {
-            Some(index) => {
-                let order = self.bids.remove(index);
+        if let Some(order) = self.bids.remove(order_id) {
+            return Ok(order);
+        }
+
+        if let Some(order) = self.asks.remove(order_id) {
+            return Ok(order);
+        }
+
+        match self.stops.remove(&order_id) {
+            Some((order, ticket)) => {
+                let key = Book::stop_key(&order, ticket);
+
+                match order.side {
+                    OrderSide::Buy => self.buy_stops.remove(&key),
+                    OrderSide::Sell => self.sell_stops.remove(&key),
+                };
+
                 Ok(order)
+            },
+            None => Err(()),
+        }
+    }
+
+    // Reduces a resting or stop order without it losing its place
+    pub fn reduce_order(&mut self, order: Order) {
+        if let Some(entry) = self.stops.get_mut(&order.id) {
+            entry.0 = order;
+            return;
+        }
+
+        match order.side {
+            OrderSide::Buy => self.bids.reduce(order),
+            OrderSide::Sell => self.asks.reduce(order),
+        }
+    }
+
+    fn stop_key(order: &Order, ticket: u64) -> (u64, u64) {
+        let stop_price = order.stop_price.unwrap();
+
+        match order.side {
+            OrderSide::Buy => (stop_price, ticket),
+            OrderSide::Sell => (!stop_price, ticket),
+        }
+    }
+
+    // Whether a stop order would trigger on the last trade price right away
+    pub fn is_triggered(&self, order: &Order) -> bool {
+        match (self.last_price, order.stop_price) {
+            (Some(last_price), Some(stop_price)) => match order.side {
+                OrderSide::Buy => last_price >= stop_price,
+                OrderSide::Sell => last_price <= stop_price,
+            },
+            _ => false,
+        }
+    }
+
+    // Holds a stop order outside the book until it triggers
+    pub fn add_stop(&mut self, order: Order) {
+        let ticket = self.next_stop_ticket;
+        self.next_stop_ticket += 1;
+
+        let key = Book::stop_key(&order, ticket);
+
+        match order.side {
+            OrderSide::Buy => self.buy_stops.insert(key, order.id),
+            OrderSide::Sell => self.sell_stops.insert(key, order.id),
+        };
+
+        if let TimeInForce::GoodTillDate(expiry) = order.time_in_force {
+            self.expiries.insert((expiry, order.id));
+        }
+
+        self.stops.insert(order.id, (order, ticket));
+    }
+
+    // The stop price of a trailing stop following a traded price
+    fn trail_price(order: &Order, price: OrderPrice) -> OrderPrice {
+        let offset = order.trailing_offset.unwrap().at(price);
+
+        match order.side {
+            OrderSide::Buy => price.saturating_add(offset),
+            OrderSide::Sell => price.saturating_sub(offset),
+        }
+    }
+
+    // Gives a trailing stop without a stop price one from the last trade price
+    pub fn start_trailing(&self, mut order: Order) -> Order {
+        if order.trailing_offset.is_some() && order.stop_price.is_none() {
+            order.stop_price = self.last_price.map(|x| Book::trail_price(&order, x));
+        }
+
+        order
+    }
+
+    // Best price of the side among orders that are not pegged, so that pegs do
+    // not follow each other
+    fn unpegged_best_price(side: &BookSide) -> Option<OrderPrice> {
+        side.iter().find(|x| x.peg.is_none()).map(|x| x.price)
+    }
+
+    // The price a pegged order follows its reference to, or None when the
+    // reference is missing or the price would overflow or not be positive.
+    // It is kept one tick away from the opposite best price so that pegs
+    // never take.
+    pub fn peg_price(&self, order: &Order, tick_size: OrderPrice) -> Option<OrderPrice> {
+        let peg = match order.peg {
+            None => return None,
+            Some(peg) => peg,
+        };
+
+        let (primary, opposite) = match order.side {
+            OrderSide::Buy => (&self.bids, &self.asks),
+            OrderSide::Sell => (&self.asks, &self.bids),
+        };
+
+        let reference = match peg.reference {
+            PegReference::Primary => Book::unpegged_best_price(primary),
+            PegReference::Opposite => Book::unpegged_best_price(opposite),
+            PegReference::Mid => {
+                match (Book::unpegged_best_price(primary), Book::unpegged_best_price(opposite)) {
+                    (Some(a), Some(b)) => Some(a / 2 + b / 2 + (a % 2 + b % 2) / 2),
+                    _ => None,
+                }
+            },
+        };
+
+        let price = match reference {
+            Some(reference) => match (reference as i64).checked_add(peg.offset) {
+                Some(price) if price > 0 => price as OrderPrice,
+                _ => return None,
+            },
+            None => return None,
+        };
+
+        let price = match order.side {
+            OrderSide::Buy => {
+                let price = price - price % tick_size;
+                let price = peg.cap.map_or(price, |x| cmp::min(price, x));
+
+                match opposite.best_price() {
+                    Some(best_ask) if price >= best_ask => best_ask.saturating_sub(tick_size),
+                    _ => price,
+                }
+            },
+            OrderSide::Sell => {
+                let price = price + (tick_size - price % tick_size) % tick_size;
+                let price = peg.cap.map_or(price, |x| cmp::max(price, x));
+
+                match opposite.best_price() {
+                    Some(best_bid) if price <= best_bid => best_bid + tick_size,
+                    _ => price,
+                }
+            },
+        };
+
+        if price == 0 {
+            None
+        } else {
+            Some(price)
+        }
+    }
+
+    // Prices a pegged order that is yet to be placed
+    pub fn start_pegging(&self, mut order: Order, tick_size: OrderPrice) -> Order {
+        if let Some(price) = self.peg_price(&order, tick_size) {
+            order.price = price;
+        }
+
+        order
+    }
+
+    // Resting pegged orders in the order they are repriced in
+    pub fn pegged_order_ids(&mut self) -> Vec<OrderId> {
+        let stale: Vec<OrderId> = {
+            let bids = &self.bids;
+            let asks = &self.asks;
+
+            self.pegged.iter()
+                .filter(|x| bids.get(**x).or_else(|| asks.get(**x)).map_or(true, |x| x.peg.is_none()))
+                .cloned()
+                .collect()
+        };
+
+        for order_id in stale {
+            self.pegged.remove(&order_id);
+        }
+
+        self.pegged.iter().cloned().collect()
+    }
+
+    // Moves a resting pegged order to its new price, behind the orders
+    // already there
+    pub fn reprice_peg(&mut self, order: Order) {
+        let side = match order.side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+
+        side.remove(order.id).unwrap();
+        side.insert(order);
+    }
+
+    // Moves trailing stops after trades between the low and high price.
+    // Triggers only ever tighten and keep their place among equal stops.
+    fn trail_stops(&mut self, low: OrderPrice, high: OrderPrice) {
+        let order_ids: Vec<OrderId> = self.stops.values()
+            .filter(|x| x.0.trailing_offset.is_some())
+            .map(|x| x.0.id)
+            .collect();
+
+        for order_id in order_ids {
+            let (order, ticket) = self.stops[&order_id];
+            let stop_price = order.stop_price.unwrap();
+
+            let trailed = match order.side {
+                OrderSide::Buy => cmp::min(stop_price, Book::trail_price(&order, low)),
+                OrderSide::Sell => cmp::max(stop_price, Book::trail_price(&order, high)),
+            };
+
+            if trailed == stop_price {
+                continue;
+            }
+
+            let trailed_order = Order { stop_price: Some(trailed), ..order };
+            let (key, trailed_key) = (Book::stop_key(&order, ticket), Book::stop_key(&trailed_order, ticket));
+
+            match order.side {
+                OrderSide::Buy => {
+                    self.buy_stops.remove(&key);
+                    self.buy_stops.insert(trailed_key, order_id);
+                },
+                OrderSide::Sell => {
+                    self.sell_stops.remove(&key);
+                    self.sell_stops.insert(trailed_key, order_id);
+                },
+            }
+
+            self.stops.insert(order_id, (trailed_order, ticket));
+        }
+    }
+
+    // Removes the stop orders the last trade price has reached, buys before
+    // sells and each by stop price, then placement. They are returned with
+    // the stop price cleared, ready to execute.
+    pub fn take_triggered_stops(&mut self) -> Vec<Order> {
+        let last_price = match self.last_price {
+            Some(last_price) => last_price,
+            None => return vec![],
+        };
+
+        let mut order_ids: Vec<OrderId> = self.buy_stops.iter()
+            .take_while(|&(key, _)| key.0 <= last_price)
+            .map(|(_, order_id)| *order_id)
+            .collect();
+
+        order_ids.extend(self.sell_stops.iter()
+            .take_while(|&(key, _)| (!key.0) >= last_price)
+            .map(|(_, order_id)| *order_id));
+
+        order_ids.into_iter().map(|order_id| {
+            let order = self.cancel_order(order_id).unwrap();
+
+            Order { stop_price: None, trailing_offset: None, ..order }
+        }).collect()
+    }
+
+    // Whether a limit order would take liquidity at its price
+    pub fn crosses(&self, order: &Order) -> bool {
+        match order.side {
+            OrderSide::Buy => self.asks.best_price().map_or(false, |x| order.price >= x),
+            OrderSide::Sell => self.bids.best_price().map_or(false, |x| order.price <= x),
+        }
+    }
+
+    // Moves a crossing post only order that slides to the nearest tick inside
+    // the opposite best price. Returns None when there is no such price.
+    pub fn slide_post_only(&self, mut order: Order) -> Option<Order> {
+        if order.post_only != Some(PostOnly::Slide) || !self.crosses(&order) {
+            return Some(order);
+        }
+
+        match order.side {
+            OrderSide::Buy => {
+                let below = self.asks.best_price().unwrap() - 1;
+                order.price = below - below % self.tick_size;
+
+                if order.price == 0 {
+                    return None;
+                }
+            },
+            OrderSide::Sell => {
+                let best_bid = self.bids.best_price().unwrap();
+                order.price = best_bid + self.tick_size - best_bid % self.tick_size;
+            },
+        }
+
+        Some(order)
+    }
+
+    // Reduces what the taker has left by a trade
+    fn fill_taker(order: &mut Order, trade: &Trade) {
+        if order.is_size_limited() {
+            order.remaining -= trade.size;
+        }
+
+        if let Some(funds) = order.funds {
+            order.funds = Some(funds - trade.price * trade.size);
+        }
+    }
+
+    // Whether the whole order would fill against the book as it is, without
+    // trading beyond the protection price. Orders of the same user are passed
+    // over, or stop the order, as self trade prevention would have them when
+    // executing.
+    fn is_fillable(&self, mut order: Order, protection: Option<OrderPrice>) -> bool {
+        let opposite = match order.side {
+            OrderSide::Buy => &self.asks,
+            OrderSide::Sell => &self.bids,
+        };
+
+        for maker in opposite.iter() {
+            if order.remaining == 0 || Book::is_beyond(&order, maker.price, protection) {
+                break;
             }
-            None => {
-                match self.asks.iter().position(|x| x.id == order_id) {
-                    Some(index) => {
-                        let order = self.asks.remove(index);
-                        Ok(order)
+
+            let trade = match Book::match_orders(maker, &order) {
+                None => break,
+                Some(trade) => trade,
+            };
+
+            if maker.user_id == order.user_id && order.self_trade_prevention.is_some() {
+                match order.self_trade_prevention.unwrap() {
+                    SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth => break,
+                    SelfTradePrevention::CancelOldest => {},
+                    SelfTradePrevention::DecrementAndCancel => order.remaining -= trade.size,
+                }
+
+                continue;
+            }
+
+            Book::fill_taker(&mut order, &trade);
+        }
+
+        order.remaining == 0
+    }
+
+    // Whether a maker price is further than the protection price the order
+    // may trade to
+    fn is_beyond(order: &Order, price: OrderPrice, protection: Option<OrderPrice>) -> bool {
+        match (order.side, protection) {
+            (OrderSide::Buy, Some(protection)) => price > protection,
+            (OrderSide::Sell, Some(protection)) => price < protection,
+            (_, None) => false,
+        }
+    }
+
+    // Executes the order as far through the book as its price allows
+    pub fn execute(&mut self, order: Order) -> Execution {
+        self.execute_within(order, None)
+    }
+
+    // Match against the best price level of the opposite side, shared among
+    // its orders by the book's matching algorithm, until the order is filled,
+    // no longer crosses or would trade beyond the protection price, then rest
+    // the remainder of orders whose time in force allows it
+    pub fn execute_within(&mut self, mut order: Order, protection: Option<OrderPrice>) -> Execution {
+        let mut trades = vec![];
+
+        if self.auction {
+            return self.rest(order, trades, vec![], false);
+        }
+
+        // Post only orders that would take are repriced or not placed at all
+        if order.post_only.is_some() {
+            match self.slide_post_only(order) {
+                Some(slid) if !self.crosses(&slid) => order = slid,
+                _ => {
+                    return Execution {
+                        order: order,
+                        trades: trades,
+                        resting: false,
+                        canceled: vec![],
+                    };
+                },
+            }
+        }
+
+        if order.time_in_force == TimeInForce::FillOrKill && !self.is_fillable(order, protection) {
+            return Execution {
+                order: order,
+                trades: trades,
+                resting: false,
+                canceled: vec![],
+            };
+        }
+
+        let mut canceled = vec![];
+        let mut is_stopped = false;
+        let matching = self.matching;
+
+        {
+            let opposite = match order.side {
+                OrderSide::Buy => &mut self.asks,
+                OrderSide::Sell => &mut self.bids,
+            };
+
+            while !is_stopped && (!order.is_size_limited() || order.remaining > 0) {
+                let makers: Vec<Order> = opposite.best_level().iter().map(|x| opposite.visible(*x).unwrap()).collect();
+
+                if makers.is_empty() || Book::is_beyond(&order, makers[0].price, protection) {
+                    break;
+                }
+
+                // Matching the whole level as one order gives how much the
+                // taker can take at its price
+                let level = Order { remaining: makers.iter().map(|x| x.remaining).sum(), ..makers[0] };
+
+                let size = match Book::match_orders(&level, &order) {
+                    None => break,
+                    Some(trade) => trade.size,
+                };
+
+                let resting: Vec<OrderSize> = makers.iter().map(|x| x.remaining).collect();
+                let allocations = matching.allocate(&resting, size);
+
+                for (maker, allocation) in makers.into_iter().zip(allocations) {
+                    if allocation == 0 {
+                        continue;
                     }
-                    None => {
-                        Err(())
+
+                    let maker_id = maker.id;
+
+                    let mut trade = match Book::match_orders(&maker, &order) {
+                        None => break,
+                        Some(trade) => trade,
+                    };
+
+                    trade.size = cmp::min(trade.size, allocation);
+
+                    if maker.user_id == order.user_id && order.self_trade_prevention.is_some() {
+                        match order.self_trade_prevention.unwrap() {
+                            SelfTradePrevention::CancelNewest => {
+                                is_stopped = true;
+                                break;
+                            },
+                            SelfTradePrevention::CancelOldest => {
+                                // What it was allocated goes to the rest of the level
+                                canceled.push(opposite.remove(maker_id).unwrap());
+                                break;
+                            },
+                            SelfTradePrevention::CancelBoth => {
+                                canceled.push(opposite.remove(maker_id).unwrap());
+                                is_stopped = true;
+                                break;
+                            },
+                            SelfTradePrevention::DecrementAndCancel => {
+                                opposite.fill(maker_id, trade.size);
+                                canceled.push(Order { remaining: trade.size, ..maker });
+
+                                // What is held for a market buy is its funds, which
+                                // are released once it has executed. A market buy
+                                // given only funds gives up what the size would
+                                // have cost, which is canceled so that it is
+                                // released too.
+                                if order.is_size_limited() {
+                                    order.remaining -= trade.size;
+
+                                    if !(order.order_type == OrderType::Market && order.side == OrderSide::Buy) {
+                                        canceled.push(Order { remaining: trade.size, ..order });
+                                    }
+                                } else {
+                                    let cost = trade.price * trade.size;
+                                    order.funds = order.funds.map(|x| x - cost);
+                                    canceled.push(Order { funds: Some(cost), ..order });
+                                }
+                            },
+                        }
+
+                        continue;
                     }
+
+                    Book::fill_taker(&mut order, &trade);
+                    opposite.fill(maker_id, trade.size);
+                    trades.push(trade);
+                }
+            }
+        }
+
+        self.record_trades(&trades);
+        self.rest(order, trades, canceled, is_stopped)
+    }
+
+    // Moves the last price and trailing stops after trades
+    fn record_trades(&mut self, trades: &[Trade]) {
+        if let Some(trade) = trades.last() {
+            self.last_price = Some(trade.price);
+
+            let low = trades.iter().map(|x| x.price).min().unwrap();
+            let high = trades.iter().map(|x| x.price).max().unwrap();
+            self.trail_stops(low, high);
+        }
+    }
+
+    // If the order is not entirely filled, insert it into the market
+    fn rest(&mut self, order: Order, trades: Vec<Trade>, canceled: Vec<Order>, is_stopped: bool) -> Execution {
+        let resting = order.can_rest() && order.remaining > 0 && !is_stopped;
+
+        if resting {
+            if let TimeInForce::GoodTillDate(expiry) = order.time_in_force {
+                self.expiries.insert((expiry, order.id));
+            }
+
+            if order.peg.is_some() {
+                self.pegged.insert(order.id);
+            }
+
+            match order.side {
+                OrderSide::Buy => self.bids.insert(order),
+                OrderSide::Sell => self.asks.insert(order),
+            }
+        }
+
+        Execution {
+            order: order,
+            trades: trades,
+            resting: resting,
+            canceled: canceled,
+        }
+    }
+
+    pub fn in_auction(&self) -> bool {
+        self.auction
+    }
+
+    // Stops matching so that orders accumulate until the auction uncrosses
+    pub fn start_auction(&mut self) {
+        self.auction = true;
+    }
+
+    // The single price the book would uncross at and the volume traded there,
+    // or None when it does not cross. The price is the one that trades the
+    // most, then leaves the least unmatched, then is the lowest. Hidden
+    // iceberg size takes part.
+    pub fn indicative_uncross(&self) -> Option<(OrderPrice, OrderSize)> {
+        let (best_bid, best_ask) = match (self.best_bid(), self.best_ask()) {
+            (Some(best_bid), Some(best_ask)) if best_bid >= best_ask => (best_bid, best_ask),
+            _ => return None,
+        };
+
+        // Only orders priced inside the crossed range can trade
+        let mut bids: BTreeMap<OrderPrice, OrderSize> = BTreeMap::new();
+        let mut asks: BTreeMap<OrderPrice, OrderSize> = BTreeMap::new();
+
+        for order in self.bids.iter().take_while(|x| x.price >= best_ask) {
+            *bids.entry(order.price).or_insert(0) += order.remaining;
+        }
+
+        for order in self.asks.iter().take_while(|x| x.price <= best_bid) {
+            *asks.entry(order.price).or_insert(0) += order.remaining;
+        }
+
+        let mut prices: Vec<OrderPrice> = bids.keys().chain(asks.keys()).cloned().collect();
+        prices.sort();
+        prices.dedup();
+
+        let mut best: Option<(OrderPrice, OrderSize, OrderSize)> = None;
+
+        for price in prices {
+            let demand: OrderSize = bids.iter().filter(|&(x, _)| *x >= price).map(|(_, size)| *size).sum();
+            let supply: OrderSize = asks.iter().filter(|&(x, _)| *x <= price).map(|(_, size)| *size).sum();
+            let volume = cmp::min(demand, supply);
+            let imbalance = cmp::max(demand, supply) - volume;
+
+            let is_better = match best {
+                None => true,
+                Some((_, best_volume, best_imbalance)) => {
+                    volume > best_volume || (volume == best_volume && imbalance < best_imbalance)
+                },
+            };
+
+            if is_better {
+                best = Some((price, volume, imbalance));
+            }
+        }
+
+        best.map(|(price, volume, _)| (price, volume))
+    }
+
+    // Shares the volume among the orders of a side that cross the price, a
+    // level at a time in price priority and within a level by the matching
+    // algorithm. Hidden iceberg size takes part.
+    fn auction_allocations(side: &BookSide, price: OrderPrice, volume: OrderSize, matching: Matching) -> Vec<(Order, OrderSize)> {
+        let crossing: Vec<Order> = side.iter()
+            .take_while(|x| if x.side == OrderSide::Buy { x.price >= price } else { x.price <= price })
+            .cloned()
+            .collect();
+
+        let mut allocations = vec![];
+        let mut left = volume;
+        let mut start = 0;
+
+        while start < crossing.len() && left > 0 {
+            let level: Vec<Order> = crossing[start..].iter().take_while(|x| x.price == crossing[start].price).cloned().collect();
+            start += level.len();
+
+            let resting: Vec<OrderSize> = level.iter().map(|x| x.remaining).collect();
+            let size = cmp::min(left, resting.iter().sum());
+            left -= size;
+
+            for (order, allocation) in level.into_iter().zip(matching.allocate(&resting, size)) {
+                if allocation > 0 {
+                    allocations.push((order, allocation));
                 }
             }
         }
+
+        allocations
     }
 
-    // find an order of the opposite type. orders are already sorted in best
-    // to worst price, allowing only looking at the first order
-    pub fn execute_order(&mut self, mut order: Order) -> Vec<Trade> {
-        let mut trades = vec![];
+    // Ends the auction by matching the orders that cross at the indicative
+    // price, as allocated on each side. Buyers are recorded as takers so that
+    // the hold above the clearing price is released when settling. Orders of
+    // the same user are not matched with each other when either prevents self
+    // trades. Neither is the newer one, so what they were allocated is
+    // canceled from both, which also keeps the book from staying crossed.
+    // Returns the trades and the canceled orders, each with the size that was
+    // canceled as its remaining size.
+    pub fn uncross(&mut self) -> (Vec<Trade>, Vec<Order>) {
+        self.auction = false;
 
-        while order.remaining > 0 {
-            // Track whether to remove the opposite order in the parent scope
-            // to avoid issues with trying to borrow self.bids/self.asks
-            // for removing the opposite order if it's filled
-            let mut filled_opposite_order = false;
-
-            {
-                let opposite_order_some = {
-                    match order.side {
-                        OrderSide::Buy => self.asks.first_mut(),
-                        OrderSide::Sell => self.bids.first_mut(),
-                    }
-                };
+        let (price, volume) = match self.indicative_uncross() {
+            None => return (vec![], vec![]),
+            Some(uncross) => uncross,
+        };
 
-                match opposite_order_some {
-                    None => {
-                        break;
-                    }
-                    Some(opposite_order) => {
-                        match Book::match_orders(&opposite_order, &order) {
-                            None => {
-                                break;
-                            }
-                            Some(trade) => {
-                                order.remaining -= trade.size;
-                                opposite_order.remaining -= trade.size;
+        let bids = Book::auction_allocations(&self.bids, price, volume, self.matching);
+        let mut asks = Book::auction_allocations(&self.asks, price, volume, self.matching);
+        let mut trades = vec![];
+        let mut canceled = vec![];
 
-                                trades.push(trade);
-                            }
-                        }
+        for (bid, mut left) in bids {
+            for &mut (ref ask, ref mut allocation) in asks.iter_mut() {
+                if left == 0 {
+                    break;
+                }
 
-                        // The opposite order has been entirely filled. Remove it
-                        // from the market.
-                        if opposite_order.remaining == 0 {
-                            filled_opposite_order = true;
-                        }
-                    }
+                let is_prevented = bid.user_id == ask.user_id &&
+                    (bid.self_trade_prevention.is_some() || ask.self_trade_prevention.is_some());
+
+                if *allocation == 0 || is_prevented {
+                    continue;
                 }
+
+                let mut trade = Trade::new(ask, &bid);
+                trade.price = price;
+                trade.size = cmp::min(left, *allocation);
+                trade.auction = true;
+
+                left -= trade.size;
+                *allocation -= trade.size;
+                trades.push(trade);
             }
 
-            if filled_opposite_order {
-                match order.side {
-                    OrderSide::Buy => { self.asks.remove(0); },
-                    OrderSide::Sell => { self.bids.remove(0); },
-                }
+            if left > 0 {
+                canceled.push(Order { remaining: left, ..bid });
             }
         }
 
-        // If the order is not entirely filled, insert it into the market
-        if order.remaining > 0 {
+        for (ask, allocation) in asks {
+            if allocation > 0 {
+                canceled.push(Order { remaining: allocation, ..ask });
+            }
+        }
+
+        for trade in trades.iter() {
+            self.bids.fill_hidden(trade.taker_order_id, trade.size);
+            self.asks.fill_hidden(trade.maker_order_id, trade.size);
+        }
+
+        for order in canceled.iter() {
             match order.side {
-                OrderSide::Buy => {
-                    let index = self.bids.iter().position(|x| x.price < order.price);
-                    match index {
-                        None => {
-                            self.bids.push(order);
-                        }
-                        Some(index) => {
-                            self.bids.insert(index, order);
-                        }
-                    }
-                },
-                OrderSide::Sell => {
-                    let index = self.asks.iter().position(|x| x.price > order.price);
-                    match index {
-                        None => {
-                            self.asks.push(order);
-                        }
-                        Some(index) => {
-                            self.asks.insert(index, order);
-                        }
-                    }
-                },
+                OrderSide::Buy => self.bids.fill_hidden(order.id, order.remaining),
+                OrderSide::Sell => self.asks.fill_hidden(order.id, order.remaining),
+            }
+        }
+
+        self.record_trades(&trades);
+        (trades, canceled)
+    }
+
+    // Removes good till date orders whose expiry is at or before the timestamp
+    pub fn expire_orders(&mut self, timestamp: u64) -> Vec<Order> {
+        let mut expired = vec![];
+
+        loop {
+            let (expiry, order_id) = match self.expiries.iter().next() {
+                Some(&entry) if entry.0 <= timestamp => entry,
+                _ => break,
+            };
+
+            self.expiries.remove(&(expiry, order_id));
+
+            // The id may since have been reused by another order
+            let is_due = self.get_order(order_id).map_or(false, |x| x.time_in_force == TimeInForce::GoodTillDate(expiry));
+
+            if is_due {
+                expired.push(self.cancel_order(order_id).unwrap());
             }
         }
 
-        trades
+        expired
+    }
+
+    pub fn execute_order(&mut self, order: Order) -> Vec<Trade> {
+        self.execute(order).trades
     }
 }
 
@@ -260,4 +1313,525 @@ mod tests {
         let canceled_order = market.cancel_order(1);
         assert!(canceled_order.is_err());
     }
+
+    #[test]
+    fn it_keeps_time_priority_when_canceling_from_middle_of_level() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(3, 1, 1, OrderSide::Sell, 1000, 10));
+        market.cancel_order(2).unwrap();
+
+        assert_eq!(market.asks.len(), 2);
+
+        let trades = market.execute_order(Order::new(4, 1, 1, OrderSide::Buy, 1000, 20));
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].maker_order_id, 1);
+        assert_eq!(trades[1].maker_order_id, 3);
+        assert_eq!(market.asks.len(), 0);
+    }
+
+    #[test]
+    fn it_tracks_best_prices() {
+        let mut market = Book::new();
+
+        assert_eq!(market.best_bid(), None);
+        assert_eq!(market.best_ask(), None);
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Buy, 990, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Buy, 995, 10));
+        market.execute_order(Order::new(3, 1, 1, OrderSide::Sell, 1010, 10));
+        market.execute_order(Order::new(4, 1, 1, OrderSide::Sell, 1005, 10));
+
+        assert_eq!(market.best_bid(), Some(995));
+        assert_eq!(market.best_ask(), Some(1005));
+
+        market.cancel_order(2).unwrap();
+        market.execute_order(Order::new(5, 1, 1, OrderSide::Buy, 1005, 10));
+
+        assert_eq!(market.best_bid(), Some(990));
+        assert_eq!(market.best_ask(), Some(1010));
+    }
+
+    #[test]
+    fn it_looks_up_resting_orders_by_id() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Buy, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 1010, 10));
+        market.execute_order(Order::new(3, 1, 1, OrderSide::Sell, 1000, 4));
+
+        assert_eq!(market.get_order(1).unwrap().remaining, 6);
+        assert_eq!(market.get_order(2).unwrap().side, OrderSide::Sell);
+        assert!(market.get_order(3).is_none());
+    }
+
+    #[test]
+    fn it_sweeps_book_with_market_order() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 1100, 10));
+
+        let execution = market.execute(Order::market(3, 1, 1, OrderSide::Buy, 25, None));
+
+        assert_eq!(execution.trades.len(), 2);
+        assert_eq!(execution.trades[1].price, 1100);
+        assert_eq!(execution.order.remaining, 5);
+        assert!(!execution.resting);
+        assert_eq!(market.asks.len(), 0);
+        assert_eq!(market.bids.len(), 0);
+    }
+
+    #[test]
+    fn it_limits_market_buy_by_funds() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 1100, 10));
+
+        // 10 @ 1000 and 4 @ 1100 with 600 left over
+        let execution = market.execute(Order::market(3, 1, 1, OrderSide::Buy, 0, Some(15000)));
+
+        assert_eq!(execution.trades.len(), 2);
+        assert_eq!(execution.trades[0].size, 10);
+        assert_eq!(execution.trades[1].size, 4);
+        assert_eq!(execution.order.funds, Some(600));
+        assert_eq!(market.asks[0].remaining, 6);
+    }
+
+    #[test]
+    fn it_cancels_remainder_of_immediate_or_cancel_order() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+
+        let mut order = Order::new(2, 1, 1, OrderSide::Buy, 1000, 15);
+        order.time_in_force = TimeInForce::ImmediateOrCancel;
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 1);
+        assert_eq!(execution.order.remaining, 5);
+        assert!(!execution.resting);
+        assert_eq!(market.bids.len(), 0);
+    }
+
+    #[test]
+    fn it_kills_fill_or_kill_order_that_cannot_fill() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 1010, 10));
+
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 1000, 15);
+        order.time_in_force = TimeInForce::FillOrKill;
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 0);
+        assert!(!execution.resting);
+        assert_eq!(market.asks.len(), 2);
+
+        order.price = 1010;
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 2);
+        assert_eq!(execution.order.remaining, 0);
+        assert_eq!(market.asks[0].remaining, 5);
+    }
+
+    #[test]
+    fn it_kills_fill_or_kill_order_that_only_fills_against_itself() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 2, 1, OrderSide::Sell, 1000, 10));
+
+        // Without its own order the book is 5 short
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 1000, 15);
+        order.time_in_force = TimeInForce::FillOrKill;
+        order.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 0);
+        assert!(execution.canceled.is_empty());
+        assert_eq!(market.asks.len(), 2);
+
+        // Decrementing accounts for the size it would have traded with itself
+        order.self_trade_prevention = Some(SelfTradePrevention::DecrementAndCancel);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 1);
+        assert_eq!(execution.trades[0].size, 5);
+        assert_eq!(execution.order.remaining, 0);
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.asks[0].remaining, 5);
+    }
+
+    #[test]
+    fn it_expires_good_till_date_orders() {
+        let mut market = Book::new();
+
+        let mut order = Order::new(1, 1, 1, OrderSide::Buy, 1000, 10);
+        order.time_in_force = TimeInForce::GoodTillDate(100);
+        market.execute_order(order);
+
+        order.id = 2;
+        order.time_in_force = TimeInForce::GoodTillDate(200);
+        market.execute_order(order);
+
+        assert_eq!(market.expire_orders(99).len(), 0);
+
+        let expired = market.expire_orders(150);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, 1);
+        assert_eq!(market.bids.len(), 1);
+
+        market.cancel_order(2).unwrap();
+        assert_eq!(market.expire_orders(200).len(), 0);
+    }
+
+    #[test]
+    fn it_does_not_take_liquidity_with_post_only_order() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Buy, 990, 10));
+
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 1000, 10);
+        order.post_only = Some(PostOnly::Reject);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 0);
+        assert!(!execution.resting);
+        assert_eq!(market.bids.len(), 1);
+
+        let mut order = Order::new(4, 1, 1, OrderSide::Sell, 980, 10);
+        order.post_only = Some(PostOnly::Slide);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 0);
+        assert!(execution.resting);
+        assert_eq!(execution.order.price, 991);
+        assert_eq!(market.best_ask(), Some(991));
+    }
+
+    #[test]
+    fn it_slides_post_only_orders_to_tick() {
+        let mut market = Book::with_rules(5, Matching::Fifo);
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Buy, 990, 10));
+
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 1000, 10);
+        order.post_only = Some(PostOnly::Slide);
+        assert_eq!(market.slide_post_only(order).map(|x| x.price), Some(995));
+
+        order.side = OrderSide::Sell;
+        order.price = 990;
+        assert_eq!(market.slide_post_only(order).map(|x| x.price), Some(995));
+
+        // There is no tick left below an ask at the first tick
+        let mut market = Book::with_rules(5, Matching::Fifo);
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 5, 10));
+
+        order.side = OrderSide::Buy;
+        order.price = 5;
+        assert!(market.slide_post_only(order).is_none());
+    }
+
+    #[test]
+    fn it_prevents_self_trades() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 2, 1, OrderSide::Sell, 1000, 10));
+
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 1000, 15);
+        order.self_trade_prevention = Some(SelfTradePrevention::CancelNewest);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 0);
+        assert!(!execution.resting);
+        assert_eq!(market.asks.len(), 2);
+
+        order.id = 4;
+        order.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.canceled.len(), 1);
+        assert_eq!(execution.canceled[0].id, 1);
+        assert_eq!(execution.trades.len(), 1);
+        assert_eq!(execution.trades[0].maker_order_id, 2);
+        assert_eq!(market.bids[0].remaining, 5);
+    }
+
+    #[test]
+    fn it_decrements_and_cancels_self_trades() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 2, 1, OrderSide::Sell, 1000, 10));
+
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 1000, 4);
+        order.self_trade_prevention = Some(SelfTradePrevention::DecrementAndCancel);
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 0);
+        assert_eq!(execution.order.remaining, 0);
+        assert_eq!(execution.canceled.len(), 2);
+        assert_eq!(market.asks[0].remaining, 6);
+
+        order.id = 4;
+        order.size = 15;
+        order.remaining = 15;
+        let execution = market.execute(order);
+
+        assert_eq!(execution.trades.len(), 1);
+        assert_eq!(execution.trades[0].size, 9);
+        assert_eq!(market.asks[0].id, 2);
+        assert_eq!(market.asks[0].remaining, 1);
+    }
+
+    #[test]
+    fn it_decrements_funds_of_market_buy_on_self_trade() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 2, 1, OrderSide::Sell, 1000, 10));
+
+        let mut order = Order::new(3, 1, 1, OrderSide::Buy, 0, 0);
+        order.order_type = OrderType::Market;
+        order.funds = Some(15000);
+        order.self_trade_prevention = Some(SelfTradePrevention::DecrementAndCancel);
+        let execution = market.execute(order);
+
+        // The 10 it would have bought from itself cost 10000 of its funds
+        assert_eq!(execution.trades.len(), 1);
+        assert_eq!(execution.trades[0].size, 5);
+        assert_eq!(execution.order.funds, Some(0));
+        assert_eq!(execution.canceled.iter().map(|x| (x.id, x.remaining, x.funds)).collect::<Vec<_>>(), vec![(1, 10, None), (3, 0, Some(10000))]);
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.asks[0].remaining, 5);
+    }
+
+    #[test]
+    fn it_triggers_stop_orders_on_last_trade_price() {
+        let mut market = Book::new();
+
+        let mut order = Order::new(1, 1, 1, OrderSide::Buy, 1100, 10);
+        order.stop_price = Some(1050);
+        market.add_stop(order);
+
+        order = Order::market(2, 1, 1, OrderSide::Sell, 10, None);
+        order.stop_price = Some(950);
+        market.add_stop(order);
+
+        assert_eq!(market.get_order(1).unwrap().stop_price, Some(1050));
+        assert_eq!(market.take_triggered_stops().len(), 0);
+
+        market.execute_order(Order::new(3, 2, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(4, 3, 1, OrderSide::Buy, 1000, 10));
+
+        assert_eq!(market.last_price(), Some(1000));
+        assert_eq!(market.take_triggered_stops().len(), 0);
+
+        market.execute_order(Order::new(5, 2, 1, OrderSide::Sell, 1050, 10));
+        market.execute_order(Order::new(6, 3, 1, OrderSide::Buy, 1050, 5));
+
+        let triggered = market.take_triggered_stops();
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].id, 1);
+        assert_eq!(triggered[0].stop_price, None);
+        assert!(market.get_order(1).is_none());
+
+        assert!(market.cancel_order(2).is_ok());
+        assert_eq!(market.take_triggered_stops().len(), 0);
+    }
+
+    #[test]
+    fn it_trails_stop_orders_behind_traded_prices() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 2, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 3, 1, OrderSide::Buy, 1000, 10));
+
+        // Sell once the price falls 10% from its high
+        let mut order = Order::market(3, 1, 1, OrderSide::Sell, 10, None);
+        order.trailing_offset = Some(TrailingOffset::Percentage(1000));
+        let order = market.start_trailing(order);
+
+        assert_eq!(order.stop_price, Some(900));
+        market.add_stop(order);
+
+        market.execute_order(Order::new(4, 2, 1, OrderSide::Sell, 1200, 10));
+        market.execute_order(Order::new(5, 3, 1, OrderSide::Buy, 1200, 5));
+
+        assert_eq!(market.get_order(3).unwrap().stop_price, Some(1080));
+
+        // Falling prices do not loosen the trigger
+        market.execute_order(Order::new(6, 3, 1, OrderSide::Buy, 1100, 10));
+        market.execute_order(Order::new(7, 2, 1, OrderSide::Sell, 1100, 5));
+
+        assert_eq!(market.get_order(3).unwrap().stop_price, Some(1080));
+        assert_eq!(market.take_triggered_stops().len(), 0);
+
+        market.execute_order(Order::new(8, 3, 1, OrderSide::Buy, 1050, 5));
+        market.execute_order(Order::new(9, 2, 1, OrderSide::Sell, 1050, 10));
+
+        let triggered = market.take_triggered_stops();
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].id, 3);
+        assert_eq!(triggered[0].trailing_offset, None);
+    }
+
+    #[test]
+    fn it_replenishes_iceberg_orders_at_back_of_level() {
+        let mut market = Book::new();
+
+        let mut order = Order::new(1, 1, 1, OrderSide::Sell, 1000, 25);
+        order.display_size = Some(10);
+        market.execute_order(order);
+        market.execute_order(Order::new(2, 2, 1, OrderSide::Sell, 1000, 5));
+
+        assert_eq!(market.asks.depth(10), vec![(1000, 15)]);
+
+        // Consumes the first slice, then the other order now ahead of the iceberg
+        let trades = market.execute_order(Order::new(3, 3, 1, OrderSide::Buy, 1000, 12));
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].maker_order_id, 1);
+        assert_eq!(trades[0].size, 10);
+        assert_eq!(trades[1].maker_order_id, 2);
+        assert_eq!(trades[1].size, 2);
+
+        assert_eq!(market.asks[0].id, 2);
+        assert_eq!(market.asks[1].remaining, 15);
+        assert_eq!(market.asks.visible(1).unwrap().remaining, 10);
+        assert_eq!(market.asks.depth(10), vec![(1000, 13)]);
+
+        // The last slice is whatever is left
+        market.execute_order(Order::new(4, 3, 1, OrderSide::Buy, 1000, 13));
+
+        assert_eq!(market.asks.visible(1).unwrap().remaining, 5);
+        assert_eq!(market.asks.depth(10), vec![(1000, 5)]);
+    }
+
+    #[test]
+    fn it_uncrosses_auction_at_single_price() {
+        let mut market = Book::new();
+        market.start_auction();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Buy, 105, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Buy, 100, 10));
+        market.execute_order(Order::new(3, 2, 1, OrderSide::Sell, 95, 5));
+        market.execute_order(Order::new(4, 2, 1, OrderSide::Sell, 100, 10));
+        market.execute_order(Order::new(5, 2, 1, OrderSide::Sell, 110, 10));
+
+        // Nothing matches while the auction runs
+        assert_eq!(market.bids.len(), 2);
+        assert_eq!(market.asks.len(), 3);
+
+        // 15 can trade at 100, with 5 of the bids left over
+        assert_eq!(market.indicative_uncross(), Some((100, 15)));
+
+        let (trades, canceled) = market.uncross();
+
+        assert!(!market.in_auction());
+        assert!(canceled.is_empty());
+        assert_eq!(trades.iter().map(|x| x.size).sum::<OrderSize>(), 15);
+        assert!(trades.iter().all(|x| x.price == 100));
+        assert_eq!(trades[0].taker_price, 105);
+        assert_eq!(market.last_price(), Some(100));
+        assert_eq!(market.bids[0].remaining, 5);
+        assert_eq!(market.asks[0].id, 5);
+    }
+
+    #[test]
+    fn it_allocates_pro_rata_with_minimum_allocation() {
+        let pro_rata = ProRata { top_order: false, min_allocation: 5 };
+
+        // The share of 2 is dropped and goes to the oldest order instead
+        assert_eq!(pro_rata.allocate(&[10, 30, 60], 20), vec![2, 6, 12]);
+
+        let pro_rata = ProRata { top_order: true, min_allocation: 5 };
+
+        assert_eq!(pro_rata.allocate(&[10, 30, 60], 20), vec![10, 4, 6]);
+        assert_eq!(pro_rata.allocate(&[10, 30, 60], 100), vec![10, 30, 60]);
+        assert_eq!(Fifo.allocate(&[10, 30, 60], 20), vec![10, 10, 0]);
+    }
+
+    #[test]
+    fn it_matches_pro_rata_across_price_level() {
+        let mut market = Book::with_rules(1, Matching::ProRata(ProRata { top_order: false, min_allocation: 1 }));
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Sell, 1000, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 1000, 30));
+        market.execute_order(Order::new(3, 1, 1, OrderSide::Sell, 1001, 10));
+
+        let trades = market.execute_order(Order::new(4, 2, 1, OrderSide::Buy, 1001, 20));
+
+        assert_eq!(trades.iter().map(|x| (x.maker_order_id, x.size)).collect::<Vec<_>>(), vec![(1, 5), (2, 15)]);
+        assert_eq!(market.asks[0].remaining, 5);
+        assert_eq!(market.asks[1].remaining, 15);
+
+        // What the best price cannot fill moves on to the next
+        let trades = market.execute_order(Order::new(5, 2, 1, OrderSide::Buy, 1001, 25));
+
+        assert_eq!(trades.iter().map(|x| (x.maker_order_id, x.size)).collect::<Vec<_>>(), vec![(1, 5), (2, 15), (3, 5)]);
+        assert_eq!(market.asks.len(), 1);
+    }
+
+    #[test]
+    fn it_prices_pegged_orders_without_crossing() {
+        let mut market = Book::new();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Buy, 100, 10));
+        market.execute_order(Order::new(2, 1, 1, OrderSide::Sell, 111, 10));
+
+        let mut order = Order::new(3, 2, 1, OrderSide::Buy, 0, 10);
+
+        // Mid is 105.5, rounded down to the tick for a buy
+        order.peg = Some(Peg { reference: PegReference::Mid, offset: 0, cap: None });
+        assert_eq!(market.peg_price(&order, 5), Some(105));
+
+        order.peg = Some(Peg { reference: PegReference::Mid, offset: 0, cap: Some(102) });
+        assert_eq!(market.peg_price(&order, 1), Some(102));
+
+        // One tick inside the best ask rather than taking it
+        order.peg = Some(Peg { reference: PegReference::Opposite, offset: 0, cap: None });
+        assert_eq!(market.peg_price(&order, 1), Some(110));
+
+        order.side = OrderSide::Sell;
+        order.peg = Some(Peg { reference: PegReference::Primary, offset: -20, cap: None });
+        assert_eq!(market.peg_price(&order, 1), Some(101));
+
+        // An offset that overflows has no price
+        order.peg = Some(Peg { reference: PegReference::Primary, offset: i64::max_value(), cap: None });
+        assert_eq!(market.peg_price(&order, 1), None);
+
+        // Nothing to follow
+        market.cancel_order(1).unwrap();
+        order.peg = Some(Peg { reference: PegReference::Opposite, offset: 0, cap: None });
+        assert_eq!(market.peg_price(&order, 1), None);
+    }
+
+    #[test]
+    fn it_uncrosses_marginal_level_by_matching_algorithm() {
+        let mut market = Book::with_rules(1, Matching::ProRata(ProRata { top_order: false, min_allocation: 1 }));
+        market.start_auction();
+
+        market.execute_order(Order::new(1, 1, 1, OrderSide::Buy, 100, 10));
+        market.execute_order(Order::new(2, 2, 1, OrderSide::Buy, 100, 30));
+        market.execute_order(Order::new(3, 3, 1, OrderSide::Sell, 100, 20));
+
+        let (trades, _) = market.uncross();
+
+        // The bids share the 20 offered in proportion rather than by time
+        assert_eq!(trades.iter().map(|x| (x.taker_order_id, x.size)).collect::<Vec<_>>(), vec![(1, 5), (2, 15)]);
+        assert!(trades.iter().all(|x| x.auction));
+        assert_eq!(market.bids[0].remaining, 5);
+        assert_eq!(market.bids[1].remaining, 15);
+    }
 }
@@ -1,5 +1,8 @@
+use std::cmp;
 use std::fs;
+use std::mem;
 use std::thread;
+use std::collections::{HashMap, VecDeque};
 use std::sync::mpsc;
 
 use journal::*;
@@ -7,19 +10,79 @@ use sequencer::*;
 use balances::*;
 use book::*;
 use messages::*;
+use utils::*;
+
+// A message submitted to a running engine and where to send its outcome
+pub struct Request {
+    pub message: Message,
+    pub reply: mpsc::Sender<Outcome>,
+}
+
+// Why an engine could not be set up
+#[derive(Debug, PartialEq)]
+pub enum StartError {
+    // Prices and sizes are checked against multiples of these
+    ZeroTickOrLotSize(MarketId),
+    Replay(ReplayError),
+}
+
+// The other leg of an OCO pair and how much of the hold both legs share
+struct OcoLink {
+    order_id: OrderId,
+    user_id: UserId,
+    asset_id: AssetId,
+    shared: Amount,
+}
 
 pub struct SuezEngine<W: JournalWriter> {
+    pub config: Config,
     pub sequencer: Sequencer,
     pub journaler: W,
-    pub book: Book,
+    // One book per configured market
+    pub books: HashMap<MarketId, Book>,
     pub balances: Balances,
+    // Both legs of each OCO pair are linked to the other until either is touched
+    links: HashMap<(MarketId, OrderId), OcoLink>,
+    // Legs canceled along with their pair while applying the current message
+    unlinked: Vec<Order>,
+    // Trade prices within the circuit breaker window of each market
+    recent_prices: HashMap<MarketId, VecDeque<(u64, OrderPrice)>>,
+    // What a circuit breaker did while applying the current message
+    tripped: Option<Report>,
 }
 
 impl<W: JournalWriter> SuezEngine<W> {
-    fn replay(&mut self) {
-        if fs::metadata("journal.json").is_err() {
+    pub fn new(config: Config, journaler: W, balances: Balances) -> Result<SuezEngine<W>, StartError> {
+        for market in config.markets.values() {
+            if market.tick_size == 0 || market.lot_size == 0 {
+                return Err(StartError::ZeroTickOrLotSize(market.id));
+            }
+        }
+
+        let books = config.markets.iter().map(|(market_id, market)| (*market_id, Book::with_rules(market.tick_size, market.matching))).collect();
+
+        Ok(SuezEngine {
+            config: config,
+            sequencer: Sequencer { sequence: 0 },
+            journaler: journaler,
+            books: books,
+            balances: balances,
+            links: HashMap::new(),
+            unlinked: vec![],
+            recent_prices: HashMap::new(),
+            tripped: None,
+        })
+    }
+
+    pub fn book(&self, market_id: MarketId) -> Option<&Book> {
+        self.books.get(&market_id)
+    }
+
+    // Stops at the first message that cannot be read or is out of sequence
+    fn replay(&mut self) -> Result<(), ReplayError> {
+        if fs::metadata("journal.binary").is_err() {
             println!("nothing to replay");
-            return;
+            return Ok(());
         }
 
         println!("replaying");
@@ -27,90 +90,949 @@ impl<W: JournalWriter> SuezEngine<W> {
         // let reader = JsonJournalReader::new("journal.json");
         let reader = BinaryJournalReader::new("journal.binary");
 
-        for message in reader.map(|x| x.unwrap()) {
-            assert_eq!(message.sequence, self.sequencer.sequence + 1);
-            self.sequencer.sequence += 1;
-            self.apply_message(&message);
+        for message in reader {
+            let sequence = self.sequencer.sequence + 1;
+            let message = try!(message.map_err(|err| ReplayError::Decode { sequence: sequence, error: err }));
+
+            if message.sequence != sequence {
+                return Err(ReplayError::OutOfSequence { expected: sequence, found: message.sequence });
+            }
+
+            self.sequencer.sequence = sequence;
+
+            // Rejections are deterministic, so a message that failed when it
+            // was first applied fails the same way here, with the same expiries,
+            // unlinks and repricing around it
+            if let Report::Rejected(err) = self.apply_message(&message).report {
+                println!("message {} rejected during replay: {:?}", message.sequence, err);
+            }
         }
 
         println!("replayed to seq {}", self.sequencer.sequence);
+        Ok(())
     }
 
-    pub fn apply_message(&mut self, message: &Message) {
-        match message.payload {
+    // Expires good till date orders in every market, in market order so that
+    // replay reports them the same way
+    pub fn expire_orders(&mut self, timestamp: u64) -> Vec<Order> {
+        let mut market_ids: Vec<MarketId> = self.books.keys().cloned().collect();
+        market_ids.sort();
+
+        let mut expired = vec![];
+
+        for market_id in market_ids {
+            for order in self.books.get_mut(&market_id).unwrap().expire_orders(timestamp) {
+                self.balances.credit_for_canceled_order(&order);
+                self.unlink(market_id, order.id);
+                expired.push(order);
+            }
+        }
+
+        expired
+    }
+
+    // Expires due orders, then applies the message and executes any stop
+    // orders its trades trigger. A message that fails to apply has already
+    // been journaled, so it gets a receipt with what else happened.
+    pub fn apply_message(&mut self, message: &Message) -> Receipt {
+        let expired = self.expire_orders(message.timestamp);
+        let mut triggered = vec![];
+        let outcome = self.apply_payload(&message.payload, message.timestamp, &mut triggered);
+        let unlinked = mem::replace(&mut self.unlinked, vec![]);
+        let tripped = self.tripped.take();
+        let repriced = self.reprice_pegs();
+
+        let indicative = message.payload.market_id()
+            .and_then(|market_id| self.books.get(&market_id))
+            .and_then(|book| if book.in_auction() { book.indicative_uncross() } else { None });
+
+        Receipt {
+            sequence: message.sequence,
+            report: outcome.unwrap_or_else(Report::Rejected),
+            expired: expired,
+            triggered: triggered,
+            unlinked: unlinked,
+            indicative: indicative,
+            tripped: tripped,
+            repriced: repriced,
+        }
+    }
+
+    // Cancels the other leg if the order is part of an OCO pair. The other leg
+    // has not been touched, so its hold is all it needs less the shared part,
+    // which stays held for the order. Both legs may already have left the
+    // book together, as when they expire at the same time, and then the
+    // caller releases the other leg in full.
+    fn unlink(&mut self, market_id: MarketId, order_id: OrderId) {
+        let link = match self.links.remove(&(market_id, order_id)) {
+            None => return,
+            Some(link) => link,
+        };
+
+        self.links.remove(&(market_id, link.order_id));
+
+        if let Ok(other) = self.books.get_mut(&market_id).unwrap().cancel_order(link.order_id) {
+            self.balances.credit_for_canceled_order(&other);
+            self.unlinked.push(other);
+        }
+
+        self.balances.hold(link.user_id, link.asset_id, link.shared);
+    }
+
+    fn apply_payload(&mut self, payload: &MessagePayload, timestamp: u64, triggered: &mut Vec<Report>) -> Result<Report, EngineError> {
+        match *payload {
             MessagePayload::CreateOrder(payload) => {
-                self.balances.debit_for_order(&payload);
+                try!(self.validate_order(&payload, timestamp));
+                let payload = self.books[&payload.market_id].start_trailing(payload);
+
+                Ok(self.place_order(payload, timestamp, triggered))
+            },
+            MessagePayload::CreateOcoOrders { limit, stop } => {
+                try!(self.validate_oco_orders(&limit, &stop, timestamp));
+                let stop = self.books[&stop.market_id].start_trailing(stop);
+                let market_id = stop.market_id;
+
+                self.place_order(stop, timestamp, triggered);
+
+                // The limit leg is placed with the legs already linked, so
+                // that filling right away cancels the stop leg
+                let (asset_id, limit_requirement) = self.balances.get_requirement_for_order(&self.prepare_order(limit));
+                let (_, stop_requirement) = self.balances.get_requirement_for_order(&stop);
+                let shared = cmp::min(limit_requirement, stop_requirement);
 
-                for trade in self.book.execute_order(payload).iter() {
-                    self.balances.settle(&trade);
+                self.links.insert((market_id, limit.id), OcoLink { order_id: stop.id, user_id: stop.user_id, asset_id: asset_id, shared: shared });
+                self.links.insert((market_id, stop.id), OcoLink { order_id: limit.id, user_id: limit.user_id, asset_id: asset_id, shared: shared });
+                self.balances.hold(stop.user_id, asset_id, -shared);
+
+                let report = self.place_order(limit, timestamp, triggered);
+
+                Ok(Report::OcoOrdersCreated {
+                    limit: Box::new(report),
+                    stop: stop,
+                })
+            },
+            MessagePayload::CancelAllOrders {
+                user_id,
+                market_id,
+                side,
+                requested_by,
+            } => {
+                let market_ids = try!(self.market_ids_for(market_id));
+                let mut canceled = vec![];
+
+                for market_id in market_ids {
+                    // Only operators cancel in halted markets
+                    if requested_by.is_some() && self.config.markets[&market_id].status == MarketStatus::Halted {
+                        continue;
+                    }
+
+                    let order_ids = self.books[&market_id].user_order_ids(user_id, side);
+
+                    for order_id in order_ids {
+                        // The other leg of a pair goes with the first, and is
+                        // reported as canceled rather than unlinked
+                        let order = match self.books.get_mut(&market_id).unwrap().cancel_order(order_id) {
+                            Ok(order) => order,
+                            Err(_) => continue,
+                        };
+
+                        self.balances.credit_for_canceled_order(&order);
+                        canceled.push(order);
+
+                        let unlinked = self.unlinked.len();
+                        self.unlink(market_id, order_id);
+                        canceled.extend(self.unlinked.drain(unlinked..));
+                    }
+                }
+
+                canceled.sort_by_key(|x| (x.market_id, x.id));
+
+                Ok(Report::OrdersCanceled(canceled))
+            },
+            MessagePayload::AmendOrder {
+                market_id,
+                order_id,
+                user_id,
+                price,
+                size,
+            } => {
+                let amended = try!(self.validate_amendment(market_id, order_id, user_id, price, size, timestamp));
+                let previous = *self.books[&market_id].get_order(order_id).unwrap();
+
+                // The hold moves from the order to the order as amended
+                self.balances.credit_for_canceled_order(&previous);
+
+                // Only a smaller size at the same price keeps time priority
+                if amended.price == previous.price && amended.remaining < previous.remaining {
+                    self.books.get_mut(&market_id).unwrap().reduce_order(amended);
+                    self.balances.debit_for_order(&amended);
+
+                    return Ok(Report::OrderReduced(amended));
                 }
+
+                self.books.get_mut(&market_id).unwrap().cancel_order(order_id).unwrap();
+
+                Ok(Report::OrderReplaced {
+                    previous: previous,
+                    report: Box::new(self.place_order(amended, timestamp, triggered)),
+                })
             },
             MessagePayload::AdjustBalance {
                 user_id,
                 asset_id,
                 change,
             } => {
-                self.balances.adjust_balance(user_id, asset_id, change);
+                Ok(Report::BalanceAdjusted {
+                    user_id: user_id,
+                    asset_id: asset_id,
+                    balance: self.balances.adjust_balance(user_id, asset_id, change),
+                })
+            },
+            MessagePayload::StartAuction { market_id } => {
+                try!(self.validate_auction(market_id, false));
+                self.books.get_mut(&market_id).unwrap().start_auction();
+
+                Ok(Report::AuctionStarted {
+                    market_id: market_id,
+                })
+            },
+            MessagePayload::EndAuction { market_id } => {
+                try!(self.validate_auction(market_id, true));
+
+                let (mut trades, canceled) = self.books.get_mut(&market_id).unwrap().uncross();
+                self.settle_trades(market_id, &mut trades, timestamp);
+
+                for order in canceled.iter() {
+                    self.balances.credit_for_canceled_order(order);
+                    self.unlink(market_id, order.id);
+                }
+
+                // Moves are measured from where the auction uncrossed
+                self.recent_prices.remove(&market_id);
+                self.record_prices(market_id, &trades, timestamp);
+
+                self.trigger_stops(market_id, timestamp, triggered);
+
+                Ok(Report::AuctionEnded {
+                    market_id: market_id,
+                    trades: trades,
+                    canceled: canceled,
+                })
+            },
+            MessagePayload::SetMarketStatus { market_id, status } => {
+                let market = try!(self.config.markets.get_mut(&market_id).ok_or(EngineError::UnknownMarket(market_id)));
+                market.status = status;
+
+                Ok(Report::MarketStatusSet {
+                    market_id: market_id,
+                    status: status,
+                })
+            },
+            MessagePayload::SetFeeTier {
+                user_id,
+                market_id,
+                tier,
+            } => {
+                try!(self.validate_fee_tier(market_id, tier));
+                self.balances.pin_fee_tier(user_id, market_id, tier);
+
+                Ok(Report::FeeTierSet {
+                    user_id: user_id,
+                    market_id: market_id,
+                    tier: tier,
+                })
             },
             MessagePayload::CancelOrder {
+                market_id,
                 order_id,
+                user_id,
             } => {
-                let order = self.book.cancel_order(order_id).unwrap();
+                try!(self.validate_cancel(market_id, order_id, user_id));
+                let order = self.books.get_mut(&market_id).unwrap().cancel_order(order_id).unwrap();
+
                 self.balances.credit_for_canceled_order(&order);
+                self.unlink(market_id, order_id);
+                Ok(Report::OrderCanceled(order))
             },
             // _ => unimplemented!(),
         }
     }
 
-    pub fn validate(&self, message: &Message) -> Result<(), String> {
-        // Validate
+    // The given market, or every market in order when None
+    fn market_ids_for(&self, market_id: Option<MarketId>) -> Result<Vec<MarketId>, EngineError> {
+        match market_id {
+            Some(market_id) if !self.books.contains_key(&market_id) => Err(EngineError::UnknownMarket(market_id)),
+            Some(market_id) => Ok(vec![market_id]),
+            None => {
+                let mut market_ids: Vec<MarketId> = self.books.keys().cloned().collect();
+                market_ids.sort();
+                Ok(market_ids)
+            },
+        }
+    }
+
+    // Places a validated order, which either waits for its stop price or
+    // executes right away and may trigger stop orders
+    fn place_order(&mut self, order: Order, timestamp: u64, triggered: &mut Vec<Report>) -> Report {
+        // Stop orders hold their funds from the start so that they
+        // can always execute once triggered
+        if order.stop_price.is_some() {
+            self.balances.debit_for_order(&order);
+            self.books.get_mut(&order.market_id).unwrap().add_stop(order);
+
+            return Report::StopOrderPlaced(order);
+        }
+
+        let report = self.execute_order(order, timestamp);
+        self.trigger_stops(order.market_id, timestamp, triggered);
+
+        report
+    }
+
+    // Holds funds for the order, executes it and settles its trades
+    fn execute_order(&mut self, order: Order, timestamp: u64) -> Report {
+        let submitted_funds = order.funds;
+        let order = self.prepare_order(order);
+        self.balances.debit_for_order(&order);
+
+        let protection = self.protection_price(&order);
+        let mut execution = self.books.get_mut(&order.market_id).unwrap().execute_within(order, protection);
+        self.settle_trades(order.market_id, &mut execution.trades, timestamp);
+
+        if self.record_prices(order.market_id, &execution.trades, timestamp) {
+            self.trip_circuit_breaker(order.market_id);
+        }
+
+        // Release whatever was held for the part that will never fill
+        if !execution.resting {
+            self.balances.credit_for_canceled_order(&execution.order);
+        }
+
+        for order in execution.canceled.iter() {
+            self.balances.credit_for_canceled_order(order);
+        }
+
+        // Any fill or cancel of a leg of an OCO pair cancels the other leg
+        let market_id = execution.order.market_id;
+
+        for order in execution.canceled.iter() {
+            self.unlink(market_id, order.id);
+        }
+
+        if !execution.resting {
+            self.unlink(market_id, execution.order.id);
+        }
+
+        // A market buy given only a size may spend the user's whole available
+        // balance, which must not be reported back as its funds
+        if submitted_funds.is_none() {
+            execution.order.funds = None;
+        }
+
+        Report::OrderCreated {
+            order: execution.order,
+            trades: execution.trades,
+            resting: execution.resting,
+            canceled: execution.canceled,
+        }
+    }
+
+    // Settles the trades and cancels the other leg of any OCO pair they fill
+    fn settle_trades(&mut self, market_id: MarketId, trades: &mut Vec<Trade>, timestamp: u64) {
+        for trade in trades.iter_mut() {
+            self.balances.settle(trade, timestamp);
+        }
+
+        for trade in trades.iter() {
+            self.unlink(market_id, trade.maker_order_id);
+            self.unlink(market_id, trade.taker_order_id);
+        }
+    }
+
+    // The furthest a market order may trade from the reference of the market's
+    // price band, which limit orders are held to when they are placed. None
+    // without a band or a reference.
+    fn protection_price(&self, order: &Order) -> Option<OrderPrice> {
+        let band = match self.config.markets[&order.market_id].price_band {
+            Some(ref band) if order.order_type == OrderType::Market => band,
+            _ => return None,
+        };
+
+        self.books[&order.market_id].reference_price(band.reference).map(|reference| {
+            let distance = percentage_of(reference, band.rate);
+
+            match order.side {
+                OrderSide::Buy => reference + distance,
+                OrderSide::Sell => reference.saturating_sub(distance),
+            }
+        })
+    }
+
+    // Adds trade prices to the market's window and drops those that have left
+    // it. Returns whether the prices now move more than the breaker allows.
+    fn record_prices(&mut self, market_id: MarketId, trades: &[Trade], timestamp: u64) -> bool {
+        let breaker = match self.config.markets[&market_id].circuit_breaker {
+            None => return false,
+            Some(ref breaker) => breaker.clone(),
+        };
+
+        if trades.is_empty() {
+            return false;
+        }
+
+        let prices = self.recent_prices.entry(market_id).or_insert_with(VecDeque::new);
+
+        for trade in trades {
+            prices.push_back((timestamp, trade.price));
+        }
+
+        while prices.front().map_or(false, |x| x.0 + breaker.window < timestamp) {
+            prices.pop_front();
+        }
+
+        let low = prices.iter().map(|x| x.1).min().unwrap();
+        let high = prices.iter().map(|x| x.1).max().unwrap();
+
+        high - low > percentage_of(low, breaker.rate)
+    }
+
+    // Halts the market or puts it in an auction, as configured
+    fn trip_circuit_breaker(&mut self, market_id: MarketId) {
+        self.recent_prices.remove(&market_id);
+
+        let market = self.config.markets.get_mut(&market_id).unwrap();
+
+        self.tripped = Some(match market.circuit_breaker.as_ref().unwrap().action {
+            CircuitBreakerAction::Halt => {
+                market.status = MarketStatus::Halted;
+
+                Report::MarketStatusSet {
+                    market_id: market_id,
+                    status: MarketStatus::Halted,
+                }
+            },
+            CircuitBreakerAction::Auction => {
+                self.books.get_mut(&market_id).unwrap().start_auction();
+
+                Report::AuctionStarted {
+                    market_id: market_id,
+                }
+            },
+        });
+    }
+
+    // Executes the stop orders reached by the last trade price until none are
+    // left, since their own trades may trigger more. What was held for each
+    // since it was placed is released and held again as it executes.
+    // Stops stay pending while the market is halted or in an auction.
+    fn trigger_stops(&mut self, market_id: MarketId, timestamp: u64, triggered: &mut Vec<Report>) {
+        loop {
+            if self.books[&market_id].in_auction() || self.config.markets[&market_id].status == MarketStatus::Halted {
+                break;
+            }
+
+            let orders = self.books.get_mut(&market_id).unwrap().take_triggered_stops();
+
+            if orders.is_empty() {
+                break;
+            }
+
+            for order in orders {
+                self.unlink(market_id, order.id);
+                self.balances.credit_for_canceled_order(&order);
+                let report = self.execute_order(order, timestamp);
+                triggered.push(report);
+            }
+        }
+    }
+
+    // Moves pegged orders that no longer sit at their pegged price. What is
+    // held for a buy moves with it, and a buy that cannot afford to move up,
+    // or whose notional would no longer fit a balance, stays where it is.
+    // Books in an auction may be crossed and halted books are frozen, so both
+    // are left alone.
+    fn reprice_pegs(&mut self) -> Vec<Order> {
+        let mut repriced = vec![];
+
+        for market_id in self.market_ids_for(None).unwrap() {
+            let tick_size = self.config.markets[&market_id].tick_size;
+            let is_halted = self.config.markets[&market_id].status == MarketStatus::Halted;
+            let book = self.books.get_mut(&market_id).unwrap();
+
+            if book.in_auction() || is_halted {
+                continue;
+            }
+
+            for order_id in book.pegged_order_ids() {
+                let order = *book.get_order(order_id).unwrap();
+
+                let moved = match book.peg_price(&order, tick_size) {
+                    Some(price) if price != order.price => Order { price: price, ..order },
+                    _ => continue,
+                };
+
+                let total = moved.price.checked_mul(moved.size);
+
+                if total.map_or(true, |x| x > i64::max_value() as u64) || !self.balances.user_can_afford_amendment(&order, &moved) {
+                    continue;
+                }
+
+                self.balances.credit_for_canceled_order(&order);
+                self.balances.debit_for_order(&moved);
+                book.reprice_peg(moved);
+                repriced.push(moved);
+            }
+        }
+
+        repriced
+    }
+
+    // Settles what an order leaves to the engine before funds are held for it.
+    // A market buy given only a size may spend the user's whole available
+    // quote balance, which is released again once the order has executed, and
+    // pegged and sliding post only orders are held at the price they will rest
+    // at.
+    fn prepare_order(&self, mut order: Order) -> Order {
+        let tick_size = self.config.markets[&order.market_id].tick_size;
+        order = self.books[&order.market_id].start_pegging(order, tick_size);
+
+        if order.order_type == OrderType::Market && order.side == OrderSide::Buy && order.funds.is_none() {
+            let quote_asset_id = self.config.markets[&order.market_id].quote_asset_id;
+            let available = self.balances.get_balance(order.user_id, quote_asset_id);
+            order.funds = Some(cmp::max(available, 0) as u64);
+        }
+
+        // Stop orders slide once they trigger
+        if order.stop_price.is_some() {
+            return order;
+        }
+
+        self.books[&order.market_id].slide_post_only(order).unwrap_or(order)
+    }
+
+    fn validate_order(&self, order: &Order, timestamp: u64) -> Result<(), EngineError> {
+        try!(self.validate_order_terms(order, timestamp));
+        try!(self.validate_market_status(order));
+
+        if self.books[&order.market_id].get_order(order.id).is_some() {
+            return Err(EngineError::DuplicateOrderId(order.id));
+        }
+
+        Ok(())
+    }
+
+    fn validate_oco_orders(&self, limit: &Order, stop: &Order, timestamp: u64) -> Result<(), EngineError> {
+        try!(self.validate_order(limit, timestamp));
+        try!(self.validate_order(stop, timestamp));
+
+        // A resting limit leg and a stop leg of the same user on the same side
+        let is_pair = limit.order_type == OrderType::Limit && limit.can_rest() && limit.stop_price.is_none() && limit.peg.is_none() &&
+            stop.stop_price.is_some() &&
+            limit.id != stop.id &&
+            limit.user_id == stop.user_id &&
+            limit.market_id == stop.market_id &&
+            limit.side == stop.side;
+
+        if !is_pair {
+            return Err(EngineError::InvalidOcoOrder(limit.id));
+        }
+
+        Ok(())
+    }
+
+    // Returns the order as amended. The size is the new total size, which must
+    // be more than has already filled.
+    fn validate_amendment(&self, market_id: MarketId, order_id: OrderId, user_id: Option<UserId>, price: Option<OrderPrice>, size: Option<OrderSize>, timestamp: u64) -> Result<Order, EngineError> {
+        let book = try!(self.books.get(&market_id).ok_or(EngineError::UnknownMarket(market_id)));
+        let previous = *try!(book.get_order(order_id).ok_or(EngineError::UnknownOrder(order_id)));
+
+        match user_id {
+            Some(user_id) if user_id != previous.user_id => return Err(EngineError::NotOrderOwner(order_id)),
+            _ => {},
+        }
+
+        if self.links.contains_key(&(market_id, order_id)) {
+            return Err(EngineError::InvalidOcoOrder(order_id));
+        }
+
+        // Pegged orders are priced by the book
+        if previous.peg.is_some() && price.is_some() {
+            return Err(EngineError::InvalidPeg(order_id));
+        }
+
+        let filled = previous.size - previous.remaining;
+        let size = size.unwrap_or(previous.size);
+
+        if size <= filled {
+            return Err(EngineError::InvalidSize(size));
+        }
+
+        let mut amended = Order {
+            price: price.unwrap_or(previous.price),
+            size: size,
+            remaining: size - filled,
+            ..previous
+        };
+
+        // Reducing an order is allowed whenever canceling it is, even when
+        // what is left would be too small or too far from the market for a
+        // new order. An iceberg shows no more than is left.
+        if amended.price == previous.price && amended.remaining < previous.remaining {
+            let market = &self.config.markets[&market_id];
+
+            if amended.remaining % market.lot_size != 0 {
+                return Err(EngineError::SizeNotOnLot(amended.remaining));
+            }
+
+            if market.status == MarketStatus::Halted && user_id.is_some() {
+                return Err(EngineError::MarketNotOpen(market_id));
+            }
+
+            amended.display_size = amended.display_size.map(|x| cmp::min(x, amended.remaining));
+
+            return Ok(amended);
+        }
+
+        // What is left must be valid as a new order
+        try!(self.validate_order_terms(&Order { size: amended.remaining, ..amended }, timestamp));
+        try!(self.validate_market_status(&amended));
+
+        Ok(amended)
+    }
+
+    fn validate_order_terms(&self, order: &Order, timestamp: u64) -> Result<(), EngineError> {
+        let book = try!(self.books.get(&order.market_id).ok_or(EngineError::UnknownMarket(order.market_id)));
+        let tick_size = self.config.markets[&order.market_id].tick_size;
+        let order = &book.start_pegging(book.start_trailing(*order), tick_size);
+
+        // Only orders that rest, or wait for a stop price, take part in an auction
+        if book.in_auction() && order.stop_price.is_none() && !order.can_rest() {
+            return Err(EngineError::AuctionInProgress(order.market_id));
+        }
+
+        if let Some(peg) = order.peg {
+            // The offset must keep clear of overflowing any price, and the cap
+            // must be a price the order could rest at
+            let is_offset_valid = peg.offset.checked_abs().map_or(false, |x| x < i64::max_value());
+            let is_cap_valid = peg.cap.map_or(true, |x| x > 0 && x % tick_size == 0);
+
+            if !is_offset_valid || !is_cap_valid || !order.can_rest() || order.stop_price.is_some() || book.peg_price(order, tick_size).is_none() {
+                return Err(EngineError::InvalidPeg(order.id));
+            }
+        }
+
+        match order.time_in_force {
+            TimeInForce::GoodTillDate(expiry) => {
+                if order.order_type == OrderType::Market || expiry <= timestamp {
+                    return Err(EngineError::InvalidTimeInForce(order.time_in_force));
+                }
+            },
+            // All or nothing needs a size to fill
+            TimeInForce::FillOrKill => {
+                if !order.is_size_limited() {
+                    return Err(EngineError::InvalidTimeInForce(order.time_in_force));
+                }
+            },
+            _ => {},
+        }
+
+        match order.order_type {
+            OrderType::Limit => {
+                if order.price == 0 {
+                    return Err(EngineError::InvalidPrice(order.price));
+                }
+
+                if let Some(funds) = order.funds {
+                    return Err(EngineError::InvalidFunds(funds));
+                }
+            },
+            OrderType::Market => {
+                if order.price != 0 {
+                    return Err(EngineError::InvalidPrice(order.price));
+                }
+
+                match (order.side, order.funds) {
+                    (OrderSide::Sell, Some(funds)) => return Err(EngineError::InvalidFunds(funds)),
+                    (OrderSide::Buy, Some(funds)) if funds == 0 || funds > i64::max_value() as u64 => {
+                        return Err(EngineError::InvalidFunds(funds));
+                    },
+                    _ => {},
+                }
+            },
+        }
+
+        // The notional must fit a balance amount. Market buys with funds may
+        // leave out the size.
+        let total = order.price.checked_mul(order.size);
+        let is_size_required = order.funds.is_none();
+
+        if (is_size_required && order.size == 0) || order.remaining != order.size || total.map_or(true, |x| x > i64::max_value() as u64) {
+            return Err(EngineError::InvalidSize(order.size));
+        }
+
+        try!(self.validate_market_rules(order));
+
+        // Only orders that may rest have anything to hide
+        if let Some(display_size) = order.display_size {
+            if display_size == 0 || display_size > order.size || !order.can_rest() {
+                return Err(EngineError::InvalidDisplaySize(display_size));
+            }
+        }
+
+        if let Some(offset) = order.trailing_offset {
+            let is_valid = match offset {
+                TrailingOffset::Absolute(offset) => offset > 0,
+                TrailingOffset::Percentage(rate) => rate > 0 && rate < 10000,
+            };
+
+            if !is_valid {
+                return Err(EngineError::InvalidTrailingOffset(offset));
+            }
+
+            // Before the first trade there is nothing to trail from, so the
+            // stop price must be given
+            if order.stop_price.is_none() {
+                return Err(EngineError::InvalidStopPrice(0));
+            }
+        }
+
+        if let Some(stop_price) = order.stop_price {
+            if stop_price == 0 || book.is_triggered(order) {
+                return Err(EngineError::InvalidStopPrice(stop_price));
+            }
+
+            // What a stop market buy may spend is held when it is placed
+            if order.order_type == OrderType::Market && order.side == OrderSide::Buy && order.funds.is_none() {
+                return Err(EngineError::InvalidFunds(0));
+            }
+        }
+
+        if order.post_only.is_some() {
+            if order.order_type == OrderType::Market {
+                return Err(EngineError::PostOnlyWouldTake);
+            }
+
+            // Stop orders are checked against the book once they trigger
+            match book.slide_post_only(*order) {
+                Some(slid) if order.stop_price.is_some() || !book.crosses(&slid) => {},
+                _ => return Err(EngineError::PostOnlyWouldTake),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_cancel(&self, market_id: MarketId, order_id: OrderId, user_id: Option<UserId>) -> Result<(), EngineError> {
+        let book = try!(self.books.get(&market_id).ok_or(EngineError::UnknownMarket(market_id)));
+        let order = try!(book.get_order(order_id).ok_or(EngineError::UnknownOrder(order_id)));
+
+        if self.config.markets[&market_id].status == MarketStatus::Halted && user_id.is_some() {
+            return Err(EngineError::MarketNotOpen(market_id));
+        }
+
+        match user_id {
+            Some(user_id) if user_id != order.user_id => Err(EngineError::NotOrderOwner(order_id)),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks the order against the market's tick size, lot size, size limits
+    // and minimum notional. Market buys given only funds have no size to check,
+    // and the stop price of a trailing stop follows trades rather than ticks.
+    fn validate_market_rules(&self, order: &Order) -> Result<(), EngineError> {
+        let market = &self.config.markets[&order.market_id];
+
+        if order.price % market.tick_size != 0 {
+            return Err(EngineError::PriceNotOnTick(order.price));
+        }
+
+        // Fat fingered limit prices are kept out of the book
+        if let Some(ref band) = market.price_band {
+            let reference = self.books[&order.market_id].reference_price(band.reference);
+
+            if let (OrderType::Limit, Some(reference)) = (order.order_type, reference) {
+                let distance = if order.price > reference { order.price - reference } else { reference - order.price };
+
+                if distance > percentage_of(reference, band.rate) {
+                    return Err(EngineError::PriceOutsideBand(order.price));
+                }
+            }
+        }
+
+        if let Some(stop_price) = order.stop_price {
+            if order.trailing_offset.is_none() && stop_price % market.tick_size != 0 {
+                return Err(EngineError::PriceNotOnTick(stop_price));
+            }
+        }
+
+        if order.is_size_limited() {
+            if order.size % market.lot_size != 0 {
+                return Err(EngineError::SizeNotOnLot(order.size));
+            }
+
+            if order.size < market.min_size {
+                return Err(EngineError::SizeBelowMinimum(order.size));
+            }
+
+            if order.size > market.max_size {
+                return Err(EngineError::SizeAboveMaximum(order.size));
+            }
+        }
+
+        if let Some(display_size) = order.display_size {
+            if display_size % market.lot_size != 0 {
+                return Err(EngineError::SizeNotOnLot(display_size));
+            }
+        }
+
+        // Sizes were checked to fit a balance amount, so the product does too
+        let notional = match order.order_type {
+            OrderType::Limit => Some(order.price * order.size),
+            OrderType::Market => order.funds,
+        };
+
+        match notional {
+            Some(notional) if notional < market.min_notional => Err(EngineError::NotionalBelowMinimum(notional)),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether the market's status lets the order be placed
+    fn validate_market_status(&self, order: &Order) -> Result<(), EngineError> {
+        let book = &self.books[&order.market_id];
+
+        // Pegs are checked at the price they would be placed at
+        let tick_size = self.config.markets[&order.market_id].tick_size;
+        let order = &book.start_pegging(*order, tick_size);
+
+        match self.config.markets[&order.market_id].status {
+            MarketStatus::Open => Ok(()),
+            MarketStatus::PostOnly => {
+                if order.order_type != OrderType::Limit || order.stop_price.is_some() {
+                    return Err(EngineError::PostOnlyWouldTake);
+                }
+
+                match book.slide_post_only(*order) {
+                    Some(placed) if !book.crosses(&placed) => Ok(()),
+                    _ => Err(EngineError::PostOnlyWouldTake),
+                }
+            },
+            MarketStatus::CancelOnly | MarketStatus::Halted => Err(EngineError::MarketNotOpen(order.market_id)),
+        }
+    }
+
+    fn validate_auction(&self, market_id: MarketId, in_auction: bool) -> Result<(), EngineError> {
+        let book = try!(self.books.get(&market_id).ok_or(EngineError::UnknownMarket(market_id)));
+
+        match (book.in_auction(), in_auction) {
+            (true, false) => Err(EngineError::AuctionInProgress(market_id)),
+            (false, true) => Err(EngineError::NoAuctionInProgress(market_id)),
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_fee_tier(&self, market_id: MarketId, tier: Option<u32>) -> Result<(), EngineError> {
+        let market = try!(self.config.markets.get(&market_id).ok_or(EngineError::UnknownMarket(market_id)));
+
+        match tier {
+            Some(tier) if tier as usize >= market.fee_tiers.len() => Err(EngineError::UnknownFeeTier(tier)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn validate(&self, message: &Message) -> Result<(), EngineError> {
         match message.payload {
             MessagePayload::CreateOrder(payload) => {
-                if !self.balances.user_can_afford_order(&payload) {
-                    // TODO: Real errors
-                    return Err("user cannot afford order".to_string());
+                try!(self.validate_order(&payload, message.timestamp));
+
+                let order = self.prepare_order(payload);
+
+                if !self.balances.user_can_afford_order(&order) || order.funds == Some(0) {
+                    return Err(EngineError::InsufficientFunds);
+                }
+
+                Ok(())
+            },
+            // Holding for the leg that needs more covers both
+            MessagePayload::CreateOcoOrders { limit, stop } => {
+                try!(self.validate_oco_orders(&limit, &stop, message.timestamp));
+
+                if !self.balances.user_can_afford_order(&self.prepare_order(limit)) || !self.balances.user_can_afford_order(&stop) {
+                    return Err(EngineError::InsufficientFunds);
                 }
+
                 Ok(())
             },
+            MessagePayload::CancelOrder { market_id, order_id, user_id } => self.validate_cancel(market_id, order_id, user_id),
+            MessagePayload::AmendOrder { market_id, order_id, user_id, price, size } => {
+                let amended = try!(self.validate_amendment(market_id, order_id, user_id, price, size, message.timestamp));
+                let previous = self.books[&market_id].get_order(order_id).unwrap();
+
+                if !self.balances.user_can_afford_amendment(previous, &self.prepare_order(amended)) {
+                    return Err(EngineError::InsufficientFunds);
+                }
+
+                Ok(())
+            },
+            MessagePayload::CancelAllOrders { user_id, market_id, requested_by, .. } => {
+                try!(self.market_ids_for(market_id));
+
+                // Halted markets are skipped unless the user asked for one
+                match (requested_by, market_id) {
+                    (Some(requested_by), _) if requested_by != user_id => Err(EngineError::UserMismatch(requested_by)),
+                    (Some(_), Some(market_id)) if self.config.markets[&market_id].status == MarketStatus::Halted => {
+                        Err(EngineError::MarketNotOpen(market_id))
+                    },
+                    _ => Ok(()),
+                }
+            },
+            MessagePayload::SetMarketStatus { market_id, .. } => self.market_ids_for(Some(market_id)).map(|_| ()),
+            MessagePayload::StartAuction { market_id } => self.validate_auction(market_id, false),
+            MessagePayload::EndAuction { market_id } => self.validate_auction(market_id, true),
+            MessagePayload::SetFeeTier { market_id, tier, .. } => self.validate_fee_tier(market_id, tier),
             _ => Ok(()),
         }
     }
 
-    pub fn process_message(&mut self, mut message: Message) {
+    pub fn process_message(&mut self, mut message: Message) -> Outcome {
         self.sequencer.apply(&mut message);
         self.journaler.write(&message).unwrap();
-        self.apply_message(&message);
+        Ok(self.apply_message(&message))
     }
 
-    pub fn start(balances: Balances) -> mpsc::Sender<Message> {
-        let (tx, rx) = mpsc::channel();
+    // Validates the message and, if valid, sequences, journals and applies it.
+    // It is validated at the time it will be applied at.
+    pub fn submit(&mut self, mut message: Message) -> Outcome {
+        self.sequencer.stamp(&mut message);
+        try!(self.validate(&message));
+        self.process_message(message)
+    }
 
-        thread::spawn(move || {
-            let mut engine = SuezEngine {
-                book: Book::new(),
-                sequencer: Sequencer { sequence: 0 },
-                // journaler: JsonJournalWriter::new("journal.json").unwrap(),
-                journaler: BinaryJournalWriter::new("journal.binary").unwrap(),
-                balances: balances,
-            };
+    // The engine takes over the balances, which should only be changed
+    // through messages from then on. The engine is set up and the journal
+    // replayed before it moves to its own thread, so that the caller learns
+    // when either fails.
+    pub fn start(config: Config, balances: Balances) -> Result<mpsc::Sender<Request>, StartError> {
+        let mut engine = try!(SuezEngine::new(
+            config,
+            // JsonJournalWriter::new("journal.json").unwrap(),
+            BinaryJournalWriter::new("journal.binary").unwrap(),
+            balances));
+
+        try!(engine.replay().map_err(StartError::Replay));
 
-            engine.replay();
+        let (tx, rx) = mpsc::channel();
 
+        thread::spawn(move || {
             loop {
-                let message: Message = rx.recv().unwrap();
+                let request: Request = rx.recv().unwrap();
+                let outcome = engine.submit(request.message);
 
-                match engine.validate(&message) {
-                    Ok(()) => {
-                        let message: Message = rx.recv().unwrap();
-                        engine.process_message(message);
-                    }
-                    Err(err) => {
-                        println!("derp err derp: {}", err);
-                    }
-                }
+                // The submitter may have gone away, which must not stop the engine
+                let _ = request.reply.send(outcome);
             }
         });
 
-        tx
+        Ok(tx)
     }
 }
@@ -40,6 +40,11 @@ impl JournalWriter for JsonJournalWriter {
     }
 }
 
+// Messages are written with bincode and no version, so a journal only replays
+// on a build with the same message layout. Journals written before orders
+// gained time in force, stops, icebergs and pegs, or before messages such as
+// amends and mass cancels changed, no longer decode, and replay stops at the
+// first of their messages with a decode error.
 pub struct BinaryJournalWriter {
     writer: BufWriter<File>,
 }
@@ -74,6 +79,21 @@ impl JournalWriter for BinaryJournalWriter {
 // use std::fs::File;
 use std::io::{BufReader, BufRead, Lines};
 
+// Why the journal could not be replayed
+#[derive(Debug, PartialEq)]
+pub enum ReplayError {
+    // The message with the sequence could not be read, as when the journal
+    // was written with another message layout
+    Decode {
+        sequence: u64,
+        error: String,
+    },
+    OutOfSequence {
+        expected: u64,
+        found: u64,
+    },
+}
+
 pub struct JsonJournalReader {
     iter: Lines<BufReader<File>>,
 }
@@ -95,11 +115,8 @@ impl Iterator for JsonJournalReader {
     fn next(&mut self) -> Option<Result<Message, String>> {
         match self.iter.next() {
             None => None,
-            Some(line) => {
-                let unwrapped_line = line.unwrap();
-                let parsed_json: Message = serde_json::from_str(&unwrapped_line).unwrap();
-                Some(Ok(parsed_json))
-            }
+            Some(Err(err)) => Some(Err(err.to_string())),
+            Some(Ok(line)) => Some(serde_json::from_str(&line).map_err(|err| err.to_string())),
         }
     }
 }
@@ -158,6 +175,7 @@ mod tests {
             let mut journaler = JsonJournalWriter::new(filename).unwrap();
             journaler.write(&super::super::messages::Message {
                 sequence: 1,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 1,
                     market_id: 3,
@@ -166,6 +184,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -196,6 +223,7 @@ mod tests {
             let mut journaler = JsonJournalWriter::new(filename).unwrap();
             journaler.write(&super::super::messages::Message {
                 sequence: 1,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 1,
                     market_id: 3,
@@ -204,11 +232,21 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
 
             journaler.write(&super::super::messages::Message {
                 sequence: 2,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 2,
                     market_id: 3,
@@ -217,6 +255,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -243,6 +290,7 @@ mod tests {
 
             journaler.write(&super::super::messages::Message {
                 sequence: 1,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 1,
                     market_id: 3,
@@ -251,6 +299,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -260,6 +317,7 @@ mod tests {
 
             journaler.write(&super::super::messages::Message {
                 sequence: 2,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 2,
                     market_id: 3,
@@ -268,6 +326,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -294,6 +361,7 @@ mod tests {
 
             journaler.write(&super::super::messages::Message {
                 sequence: 1,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 1,
                     market_id: 3,
@@ -302,6 +370,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -311,6 +388,7 @@ mod tests {
 
             journaler.write(&super::super::messages::Message {
                 sequence: 2,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 2,
                     market_id: 3,
@@ -319,6 +397,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -339,6 +426,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_round_trips_amend_order_in_binary() {
+        let filename = "journal-amend.dat";
+
+        if fs::metadata(filename).is_ok() {
+            remove_file(filename).unwrap();
+        }
+
+        {
+            let mut journaler = BinaryJournalWriter::new(filename).unwrap();
+
+            journaler.write(&super::super::messages::Message {
+                sequence: 1,
+                timestamp: 0,
+                payload: MessagePayload::AmendOrder {
+                    market_id: 3,
+                    order_id: 1,
+                    user_id: Some(2),
+                    price: Some(105),
+                    size: None,
+                },
+            }).unwrap();
+        }
+
+        let mut reader = BinaryJournalReader::new(filename);
+        let message = reader.next().unwrap().unwrap();
+
+        assert_eq!(message.payload, MessagePayload::AmendOrder {
+            market_id: 3,
+            order_id: 1,
+            user_id: Some(2),
+            price: Some(105),
+            size: None,
+        });
+    }
+
     #[test]
     fn it_handles_binary_eof() {
         let filename = "journal-binary-eof.dat";
@@ -352,6 +475,7 @@ mod tests {
 
             journaler.write(&super::super::messages::Message {
                 sequence: 1,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 1,
                     market_id: 3,
@@ -360,6 +484,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
@@ -369,6 +502,7 @@ mod tests {
 
             journaler.write(&super::super::messages::Message {
                 sequence: 2,
+                timestamp: 0,
                 payload: super::super::messages::MessagePayload::CreateOrder(Order {
                     id: 2,
                     market_id: 3,
@@ -377,6 +511,15 @@ mod tests {
                     size: 50,
                     remaining: 50,
                     user_id: 2,
+                    order_type: OrderType::Limit,
+                    funds: None,
+                    time_in_force: TimeInForce::GoodTillCanceled,
+                    post_only: None,
+                    self_trade_prevention: None,
+                    stop_price: None,
+                    trailing_offset: None,
+                    display_size: None,
+                    peg: None,
                 }),
             }).unwrap();
         }
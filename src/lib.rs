@@ -6,6 +6,7 @@ extern crate serde_json;
 extern crate bincode;
 extern crate rustc_serialize;
 extern crate websocket;
+extern crate time;
 
 pub mod utils;
 pub mod messages;
@@ -7,6 +7,8 @@ use utils::*;
 #[derive(RustcEncodable, RustcDecodable, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Message {
     pub sequence: u64,
+    // Seconds since the epoch, assigned when sequenced
+    pub timestamp: u64,
     pub payload: MessagePayload,
 }
 
@@ -14,12 +16,203 @@ pub struct Message {
 #[derive(Serialize, Deserialize, Debug, RustcEncodable, RustcDecodable, PartialEq)]
 pub enum MessagePayload {
     CreateOrder(Order),
+    // A limit order and a stop order on the same side where either filling,
+    // triggering or being canceled cancels the other. Funds are held for
+    // whichever leg needs more rather than for both.
+    CreateOcoOrders {
+        limit: Order,
+        stop: Order,
+    },
+    // Users may only cancel their own orders. Operators cancel any order by
+    // leaving out the user.
     CancelOrder {
-        order_id: u64,
+        market_id: MarketId,
+        order_id: OrderId,
+        user_id: Option<UserId>,
+    },
+    // Cancels every order of the user, or only those in the market and/or on
+    // the side given. Users may only cancel their own orders and leave halted
+    // markets alone. Operators send it without a requester.
+    CancelAllOrders {
+        user_id: UserId,
+        market_id: Option<MarketId>,
+        side: Option<OrderSide>,
+        requested_by: Option<UserId>,
+    },
+    // Changes the price and/or the total size of an order in one step. Like
+    // cancels, the user is None when sent by an operator.
+    AmendOrder {
+        market_id: MarketId,
+        order_id: OrderId,
+        user_id: Option<UserId>,
+        price: Option<OrderPrice>,
+        size: Option<OrderSize>,
     },
     AdjustBalance {
         user_id: UserId,
         asset_id: AssetId,
         change: i64,
+    },
+    // Puts the market in a call auction where orders rest without matching
+    StartAuction {
+        market_id: MarketId,
+    },
+    // Uncrosses the book at a single price and resumes continuous matching
+    EndAuction {
+        market_id: MarketId,
+    },
+    // Changes what the market accepts, such as halting it in an emergency
+    SetMarketStatus {
+        market_id: MarketId,
+        status: MarketStatus,
+    },
+    // Pins a user to a fee tier of the market regardless of volume, or
+    // unpins them when the tier is None
+    SetFeeTier {
+        user_id: UserId,
+        market_id: MarketId,
+        tier: Option<u32>,
+    },
+}
+
+impl MessagePayload {
+    // The market the message applies to, if it is about a single market
+    pub fn market_id(&self) -> Option<MarketId> {
+        match *self {
+            MessagePayload::CreateOrder(ref order) => Some(order.market_id),
+            MessagePayload::CreateOcoOrders { ref limit, .. } => Some(limit.market_id),
+            MessagePayload::CancelOrder { market_id, .. } => Some(market_id),
+            MessagePayload::CancelAllOrders { market_id, .. } => market_id,
+            MessagePayload::AmendOrder { market_id, .. } => Some(market_id),
+            MessagePayload::AdjustBalance { .. } => None,
+            MessagePayload::StartAuction { market_id } => Some(market_id),
+            MessagePayload::EndAuction { market_id } => Some(market_id),
+            MessagePayload::SetMarketStatus { market_id, .. } => Some(market_id),
+            MessagePayload::SetFeeTier { market_id, .. } => Some(market_id),
+        }
     }
 }
+
+// What applying a message did
+#[derive(Debug, PartialEq)]
+pub enum Report {
+    // The message was journaled but could not be applied
+    Rejected(EngineError),
+    // The order after matching, with the trades it took part in as taker and
+    // whether the remainder rests in the book or was canceled. Orders reduced
+    // by self trade prevention are listed with the size canceled as remaining,
+    // or the funds canceled for a market buy given only funds.
+    OrderCreated {
+        order: Order,
+        trades: Vec<Trade>,
+        resting: bool,
+        canceled: Vec<Order>,
+    },
+    // A stop order was accepted and waits outside the book to trigger
+    StopOrderPlaced(Order),
+    // The stop leg waits to trigger while the limit leg was placed as reported
+    OcoOrdersCreated {
+        limit: Box<Report>,
+        stop: Order,
+    },
+    OrderCanceled(Order),
+    // Orders canceled by a mass cancel, by market and then order id
+    OrdersCanceled(Vec<Order>),
+    // The order was reduced in place and kept its time priority
+    OrderReduced(Order),
+    // The order was canceled and placed again as amended, as reported
+    OrderReplaced {
+        previous: Order,
+        report: Box<Report>,
+    },
+    BalanceAdjusted {
+        user_id: UserId,
+        asset_id: AssetId,
+        balance: i64,
+    },
+    AuctionStarted {
+        market_id: MarketId,
+    },
+    // The trades the book uncrossed with, all at the clearing price, and the
+    // orders reduced to prevent self trades
+    AuctionEnded {
+        market_id: MarketId,
+        trades: Vec<Trade>,
+        canceled: Vec<Order>,
+    },
+    MarketStatusSet {
+        market_id: MarketId,
+        status: MarketStatus,
+    },
+    FeeTierSet {
+        user_id: UserId,
+        market_id: MarketId,
+        tier: Option<u32>,
+    },
+}
+
+// Returned to the submitter once a message has been sequenced and applied
+#[derive(Debug, PartialEq)]
+pub struct Receipt {
+    pub sequence: u64,
+    pub report: Report,
+    // Good till date orders that expired before the message was applied
+    pub expired: Vec<Order>,
+    // Stop orders triggered by trades of the message, reported as created in
+    // the order they executed
+    pub triggered: Vec<Report>,
+    // Legs of OCO pairs canceled because the other leg filled, triggered, expired
+    // or was canceled
+    pub unlinked: Vec<Order>,
+    // The price and volume the market of the message would uncross at, while
+    // it is in an auction
+    pub indicative: Option<(OrderPrice, OrderSize)>,
+    // The halt or auction a circuit breaker started after the message's trades
+    pub tripped: Option<Report>,
+    // Pegged orders moved to follow the book after the message
+    pub repriced: Vec<Order>,
+}
+
+// Why a message was rejected
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EngineError {
+    InsufficientFunds,
+    UnknownMarket(MarketId),
+    UnknownOrder(OrderId),
+    InvalidPrice(OrderPrice),
+    InvalidSize(OrderSize),
+    InvalidFunds(u64),
+    InvalidTimeInForce(TimeInForce),
+    // A post only order would have taken liquidity
+    PostOnlyWouldTake,
+    DuplicateOrderId(OrderId),
+    // The order does not follow the market's tick, lot, size or notional rules
+    PriceNotOnTick(OrderPrice),
+    SizeNotOnLot(OrderSize),
+    SizeBelowMinimum(OrderSize),
+    SizeAboveMaximum(OrderSize),
+    NotionalBelowMinimum(u64),
+    // The limit price is outside the market's price band
+    PriceOutsideBand(OrderPrice),
+    // The market's status does not allow the message
+    MarketNotOpen(MarketId),
+    // Orders that cannot rest are not accepted during an auction
+    AuctionInProgress(MarketId),
+    NoAuctionInProgress(MarketId),
+    // The order belongs to another user
+    NotOrderOwner(OrderId),
+    // Users may only act for themselves
+    UserMismatch(UserId),
+    // The legs of an OCO pair do not match, or the order is a leg and cannot
+    // be amended
+    InvalidOcoOrder(OrderId),
+    // Pegs must be limit orders that rest, with a reference to be priced from
+    InvalidPeg(OrderId),
+    UnknownFeeTier(u32),
+    // Zero, or already reached by the last trade price
+    InvalidStopPrice(OrderPrice),
+    InvalidTrailingOffset(TrailingOffset),
+    InvalidDisplaySize(OrderSize),
+}
+
+pub type Outcome = Result<Receipt, EngineError>;
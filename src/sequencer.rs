@@ -1,3 +1,4 @@
+use time;
 use messages::*;
 
 pub struct Sequencer {
@@ -12,5 +13,14 @@ impl Sequencer {
             assert_eq!(message.sequence, self.sequence + 1);
         }
         self.sequence += 1;
+
+        self.stamp(message);
+    }
+
+    // Gives the message the current time unless it already has one
+    pub fn stamp(&self, message: &mut Message) {
+        if message.timestamp == 0 {
+            message.timestamp = time::get_time().sec as u64;
+        }
     }
 }
@@ -17,90 +17,460 @@ use journal::*;
 
 pub struct SuezServerReceiver {
     send_tx: mpsc::Sender<String>,
-    engine_tx: mpsc::Sender<Message>,
+    engine_tx: mpsc::Sender<Request>,
 }
 
-impl SuezServerReceiver {
-    fn handle_create_order(&mut self, params: &Vec<Json>) -> Result<Json, String> {
-        let desc = params.get(0).unwrap().as_object().unwrap();
-        let size = parse_decimal(desc.get("size").unwrap().as_string().unwrap(), 3, true);
-        let price = parse_decimal(desc.get("price").unwrap().as_string().unwrap(), 2, true);
-        let side = if desc.get("side").unwrap().as_string().unwrap() == "buy" { OrderSide::Buy } else { OrderSide::Sell };
-        let order_id = time::precise_time_ns();
+fn trade_to_json(trade: &Trade) -> Json {
+    let mut json = BTreeMap::new();
+    json.insert("price".to_string(), trade.price.to_json());
+    json.insert("size".to_string(), trade.size.to_json());
+    json.insert("maker_order_id".to_string(), trade.maker_order_id.to_json());
+    json.insert("fee".to_string(), trade.taker_fee.to_json());
+    Json::Object(json)
+}
 
-        let payload = Order {
-            id: order_id,
-            market_id: 1,
-            user_id: 1,
-            side: side,
-            price: price.unwrap(),
-            size: size.unwrap(),
-            remaining: size.unwrap(),
-        };
+// The error sent when a request cannot be read, naming what was wrong with it
+fn invalid_request(field: &str) -> Json {
+    let mut json = BTreeMap::new();
+    json.insert("code".to_string(), "invalid_request".to_json());
+    json.insert("field".to_string(), field.to_json());
+    Json::Object(json)
+}
+
+// The object describing the request at the index of its parameters
+fn param(params: &Vec<Json>, index: usize) -> Result<&BTreeMap<String, Json>, Json> {
+    params.get(index).and_then(|x| x.as_object()).ok_or_else(|| invalid_request("params"))
+}
+
+// A decimal sent as a string, if it was sent at all
+fn decimal_from_json(desc: &BTreeMap<String, Json>, key: &str, decimal_places: u32) -> Result<Option<u64>, Json> {
+    match desc.get(key) {
+        None => Ok(None),
+        Some(value) => {
+            value.as_string()
+                .and_then(|x| parse_decimal(x, decimal_places, true).ok())
+                .map(Some)
+                .ok_or_else(|| invalid_request(key))
+        },
+    }
+}
+
+// Reads an order as described by a client
+fn order_from_json(desc: &BTreeMap<String, Json>, order_id: OrderId) -> Result<Order, Json> {
+    let decimal = |key: &str, decimal_places: u32| decimal_from_json(desc, key, decimal_places);
+
+    // Market orders have no price, and market buys may give funds instead of size
+    let order_type = match desc.get("type").and_then(|x| x.as_string()) {
+        Some("market") => OrderType::Market,
+        _ => OrderType::Limit,
+    };
+    let time_in_force = match desc.get("time_in_force").and_then(|x| x.as_string()) {
+        Some("IOC") => TimeInForce::ImmediateOrCancel,
+        Some("FOK") => TimeInForce::FillOrKill,
+        Some("GTD") => match desc.get("expire_time").and_then(|x| x.as_u64()) {
+            Some(expire_time) => TimeInForce::GoodTillDate(expire_time),
+            None => return Err(invalid_request("expire_time")),
+        },
+        _ => TimeInForce::GoodTillCanceled,
+    };
+    let post_only = match desc.get("post_only").and_then(|x| x.as_string()) {
+        Some("reject") => Some(PostOnly::Reject),
+        Some("slide") => Some(PostOnly::Slide),
+        _ => None,
+    };
+    let self_trade_prevention = match desc.get("stp").and_then(|x| x.as_string()) {
+        Some("cn") => Some(SelfTradePrevention::CancelNewest),
+        Some("co") => Some(SelfTradePrevention::CancelOldest),
+        Some("cb") => Some(SelfTradePrevention::CancelBoth),
+        Some("dc") => Some(SelfTradePrevention::DecrementAndCancel),
+        _ => None,
+    };
+    let size = try!(decimal("size", 3)).unwrap_or(0);
+    let price = try!(decimal("price", 2)).unwrap_or(0);
+    let funds = try!(decimal("funds", 5));
+    let display_size = try!(decimal("display_size", 3));
+    let stop_price = try!(decimal("stop_price", 2));
+    // Trailing offsets are a price or a percentage with two decimals
+    let trailing_offset = match (try!(decimal("trailing_offset", 2)), try!(decimal("trailing_percent", 2))) {
+        (Some(offset), _) => Some(TrailingOffset::Absolute(offset)),
+        (None, Some(rate)) => Some(TrailingOffset::Percentage(rate)),
+        (None, None) => None,
+    };
+    // Peg offsets are in the smallest price unit and may be negative
+    let peg_reference = match desc.get("peg").and_then(|x| x.as_string()) {
+        Some("primary") => Some(PegReference::Primary),
+        Some("opposite") => Some(PegReference::Opposite),
+        Some("mid") => Some(PegReference::Mid),
+        _ => None,
+    };
+    let peg_cap = try!(decimal("peg_cap", 2));
+    let peg = peg_reference.map(|reference| Peg {
+        reference: reference,
+        offset: desc.get("peg_offset").and_then(|x| x.as_i64()).unwrap_or(0),
+        cap: peg_cap,
+    });
+    let side = match desc.get("side").and_then(|x| x.as_string()) {
+        Some("buy") => OrderSide::Buy,
+        Some("sell") => OrderSide::Sell,
+        _ => return Err(invalid_request("side")),
+    };
+    let market_id = desc.get("market_id").and_then(|x| x.as_u64()).unwrap_or(1) as MarketId;
+
+    Ok(Order {
+        id: order_id,
+        market_id: market_id,
+        user_id: 1,
+        side: side,
+        price: price,
+        size: size,
+        remaining: size,
+        order_type: order_type,
+        funds: funds,
+        time_in_force: time_in_force,
+        post_only: post_only,
+        self_trade_prevention: self_trade_prevention,
+        stop_price: stop_price,
+        trailing_offset: trailing_offset,
+        display_size: display_size,
+        peg: peg,
+    })
+}
 
-        let message = Message {
-            sequence: 0,
-            payload: MessagePayload::CreateOrder(payload),
+impl ToJson for EngineError {
+    fn to_json(&self) -> Json {
+        let mut json = BTreeMap::new();
+
+        let code = match *self {
+            EngineError::InsufficientFunds => "insufficient_funds",
+            EngineError::UnknownMarket(market_id) => {
+                json.insert("market_id".to_string(), market_id.to_json());
+                "unknown_market"
+            },
+            EngineError::UnknownOrder(order_id) => {
+                json.insert("order_id".to_string(), order_id.to_json());
+                "unknown_order"
+            },
+            EngineError::InvalidPrice(price) => {
+                json.insert("price".to_string(), price.to_json());
+                "invalid_price"
+            },
+            EngineError::InvalidSize(size) => {
+                json.insert("size".to_string(), size.to_json());
+                "invalid_size"
+            },
+            EngineError::InvalidFunds(funds) => {
+                json.insert("funds".to_string(), funds.to_json());
+                "invalid_funds"
+            },
+            EngineError::InvalidTimeInForce(_) => "invalid_time_in_force",
+            EngineError::PostOnlyWouldTake => "post_only_would_take",
+            EngineError::DuplicateOrderId(order_id) => {
+                json.insert("order_id".to_string(), order_id.to_json());
+                "duplicate_order_id"
+            },
+            EngineError::InvalidOcoOrder(order_id) => {
+                json.insert("order_id".to_string(), order_id.to_json());
+                "invalid_oco_order"
+            },
+            EngineError::InvalidPeg(order_id) => {
+                json.insert("order_id".to_string(), order_id.to_json());
+                "invalid_peg"
+            },
+            EngineError::PriceNotOnTick(price) => {
+                json.insert("price".to_string(), price.to_json());
+                "price_not_on_tick"
+            },
+            EngineError::SizeNotOnLot(size) => {
+                json.insert("size".to_string(), size.to_json());
+                "size_not_on_lot"
+            },
+            EngineError::SizeBelowMinimum(size) => {
+                json.insert("size".to_string(), size.to_json());
+                "size_below_minimum"
+            },
+            EngineError::SizeAboveMaximum(size) => {
+                json.insert("size".to_string(), size.to_json());
+                "size_above_maximum"
+            },
+            EngineError::NotionalBelowMinimum(notional) => {
+                json.insert("notional".to_string(), notional.to_json());
+                "notional_below_minimum"
+            },
+            EngineError::PriceOutsideBand(price) => {
+                json.insert("price".to_string(), price.to_json());
+                "price_outside_band"
+            },
+            EngineError::MarketNotOpen(market_id) => {
+                json.insert("market_id".to_string(), market_id.to_json());
+                "market_not_open"
+            },
+            EngineError::AuctionInProgress(market_id) => {
+                json.insert("market_id".to_string(), market_id.to_json());
+                "auction_in_progress"
+            },
+            EngineError::NoAuctionInProgress(market_id) => {
+                json.insert("market_id".to_string(), market_id.to_json());
+                "no_auction_in_progress"
+            },
+            EngineError::NotOrderOwner(order_id) => {
+                json.insert("order_id".to_string(), order_id.to_json());
+                "not_order_owner"
+            },
+            EngineError::UserMismatch(user_id) => {
+                json.insert("user_id".to_string(), user_id.to_json());
+                "user_mismatch"
+            },
+            EngineError::UnknownFeeTier(tier) => {
+                json.insert("tier".to_string(), tier.to_json());
+                "unknown_fee_tier"
+            },
+            EngineError::InvalidStopPrice(stop_price) => {
+                json.insert("stop_price".to_string(), stop_price.to_json());
+                "invalid_stop_price"
+            },
+            EngineError::InvalidTrailingOffset(_) => "invalid_trailing_offset",
+            EngineError::InvalidDisplaySize(display_size) => {
+                json.insert("display_size".to_string(), display_size.to_json());
+                "invalid_display_size"
+            },
         };
 
-        self.engine_tx.send(message).unwrap();
+        json.insert("code".to_string(), code.to_json());
+        Json::Object(json)
+    }
+}
+
+impl SuezServerReceiver {
+    // Hands the payload to the engine and waits for it to be applied or rejected
+    fn submit(&mut self, payload: MessagePayload) -> Outcome {
+        let (reply_tx, reply_rx) = mpsc::channel();
+
+        self.engine_tx.send(Request {
+            message: Message {
+                sequence: 0,
+                timestamp: 0,
+                payload: payload,
+            },
+            reply: reply_tx,
+        }).unwrap();
+
+        reply_rx.recv().unwrap()
+    }
+
+    fn submit_json(&mut self, payload: MessagePayload) -> Result<Receipt, Json> {
+        match self.submit(payload) {
+            Ok(Receipt { report: Report::Rejected(err), .. }) | Err(err) => Err(err.to_json()),
+            Ok(receipt) => Ok(receipt),
+        }
+    }
+
+    fn handle_create_order(&mut self, params: &Vec<Json>) -> Result<Json, Json> {
+        let payload = try!(order_from_json(try!(param(params, 0)), time::precise_time_ns()));
+
+        let receipt = try!(self.submit_json(MessagePayload::CreateOrder(payload)));
+
+        let mut response = BTreeMap::new();
+        response.insert("sequence".to_string(), receipt.sequence.to_json());
+
+        match receipt.report {
+            Report::OrderCreated { order, trades, resting, canceled } => {
+                response.insert("order_id".to_string(), order.id.to_json());
+                response.insert("remaining".to_string(), order.remaining.to_json());
+                response.insert("resting".to_string(), resting.to_json());
+
+                if let Some(funds) = order.funds {
+                    response.insert("remaining_funds".to_string(), funds.to_json());
+                }
+
+                response.insert("trades".to_string(), Json::Array(trades.iter().map(trade_to_json).collect()));
+                response.insert("self_trade_canceled".to_string(), Json::Array(canceled.iter().map(|x| x.id.to_json()).collect()));
+            },
+            Report::StopOrderPlaced(order) => {
+                response.insert("order_id".to_string(), order.id.to_json());
+                response.insert("stop_price".to_string(), order.stop_price.to_json());
+            },
+            _ => {},
+        }
+
+        let triggered: Vec<Json> = receipt.triggered.iter().filter_map(|report| match *report {
+            Report::OrderCreated { ref order, .. } => Some(order.id.to_json()),
+            _ => None,
+        }).collect();
+        response.insert("triggered".to_string(), Json::Array(triggered));
+
+        if let Some((price, volume)) = receipt.indicative {
+            response.insert("indicative_price".to_string(), price.to_json());
+            response.insert("indicative_volume".to_string(), volume.to_json());
+        }
+
+        match receipt.tripped {
+            Some(Report::MarketStatusSet { .. }) => { response.insert("circuit_breaker".to_string(), "halted".to_json()); },
+            Some(Report::AuctionStarted { .. }) => { response.insert("circuit_breaker".to_string(), "auction".to_json()); },
+            _ => {},
+        }
+
+        Ok(Json::Object(response))
+    }
+
+    // Takes the limit leg and the stop leg as two orders
+    fn handle_create_oco_orders(&mut self, params: &Vec<Json>) -> Result<Json, Json> {
+        let order_id = time::precise_time_ns();
+        let limit = try!(order_from_json(try!(param(params, 0)), order_id));
+        let stop = try!(order_from_json(try!(param(params, 1)), order_id + 1));
+
+        let receipt = try!(self.submit_json(MessagePayload::CreateOcoOrders {
+            limit: limit,
+            stop: stop,
+        }));
 
         let mut response = BTreeMap::new();
-        response.insert("order_id".to_string(), payload.id.to_json());
+        response.insert("sequence".to_string(), receipt.sequence.to_json());
+        response.insert("limit_order_id".to_string(), limit.id.to_json());
+        response.insert("stop_order_id".to_string(), stop.id.to_json());
+
+        if let Report::OcoOrdersCreated { limit, .. } = receipt.report {
+            if let Report::OrderCreated { order, trades, resting, .. } = *limit {
+                response.insert("remaining".to_string(), order.remaining.to_json());
+                response.insert("resting".to_string(), resting.to_json());
+                response.insert("trades".to_string(), Json::Array(trades.iter().map(trade_to_json).collect()));
+            }
+        }
+
+        response.insert("unlinked".to_string(), Json::Array(receipt.unlinked.iter().map(|x| x.id.to_json()).collect()));
 
         Ok(Json::Object(response))
     }
 
-    fn handle_cancel_order(&mut self, params: &Vec<Json>) -> Result<Json, String> {
-        let desc = params.get(0).unwrap().as_object().unwrap();
-        let order_id = desc.get("size").unwrap().as_u64().unwrap();
-        // TODO: user_id
+    fn handle_cancel_order(&mut self, params: &Vec<Json>) -> Result<Json, Json> {
+        let desc = try!(param(params, 0));
+        let order_id = try!(desc.get("order_id").and_then(|x| x.as_u64()).ok_or_else(|| invalid_request("order_id")));
+        let market_id = desc.get("market_id").and_then(|x| x.as_u64()).unwrap_or(1) as MarketId;
+        let user_id = 1; // TODO
 
-        let message = Message {
-            sequence: 0,
-            payload: MessagePayload::CancelOrder {
-                order_id: order_id,
-            },
+        let receipt = try!(self.submit_json(MessagePayload::CancelOrder {
+            market_id: market_id,
+            order_id: order_id,
+            user_id: Some(user_id),
+        }));
+
+        let mut response = BTreeMap::new();
+        response.insert("sequence".to_string(), receipt.sequence.to_json());
+
+        if let Report::OrderCanceled(order) = receipt.report {
+            response.insert("order_id".to_string(), order.id.to_json());
+            response.insert("canceled_size".to_string(), order.remaining.to_json());
+        }
+
+        Ok(Json::Object(response))
+    }
+
+    fn handle_cancel_all_orders(&mut self, params: &Vec<Json>) -> Result<Json, Json> {
+        let desc = try!(param(params, 0));
+        let user_id = 1; // TODO
+        let market_id = desc.get("market_id").and_then(|x| x.as_u64()).map(|x| x as MarketId);
+        let side = match desc.get("side").and_then(|x| x.as_string()) {
+            Some("buy") => Some(OrderSide::Buy),
+            Some("sell") => Some(OrderSide::Sell),
+            _ => None,
         };
 
-        self.engine_tx.send(message).unwrap();
+        let receipt = try!(self.submit_json(MessagePayload::CancelAllOrders {
+            user_id: user_id,
+            market_id: market_id,
+            side: side,
+            requested_by: Some(user_id),
+        }));
+
+        let mut response = BTreeMap::new();
+        response.insert("sequence".to_string(), receipt.sequence.to_json());
+
+        if let Report::OrdersCanceled(orders) = receipt.report {
+            response.insert("order_ids".to_string(), Json::Array(orders.iter().map(|x| x.id.to_json()).collect()));
+        }
 
-        let response = BTreeMap::new();
         Ok(Json::Object(response))
     }
 
-    fn handle_adjust_balance(&mut self, params: &Vec<Json>) -> Result<Json, String> {
-        let desc = params.get(0).unwrap().as_object().unwrap();
-        let amount = parse_decimal(desc.get("amount").unwrap().as_string().unwrap(), 10, true).unwrap();
+    fn handle_amend_order(&mut self, params: &Vec<Json>) -> Result<Json, Json> {
+        let desc = try!(param(params, 0));
+        let order_id = try!(desc.get("order_id").and_then(|x| x.as_u64()).ok_or_else(|| invalid_request("order_id")));
+        let market_id = desc.get("market_id").and_then(|x| x.as_u64()).unwrap_or(1) as MarketId;
         let user_id = 1; // TODO
-        let asset_id = if desc.get("asset").unwrap().as_string().unwrap() == "BTC" { 1 } else { 2 };
 
-        let message = Message {
-            sequence: 0,
-            payload: MessagePayload::AdjustBalance {
-                user_id: user_id,
-                asset_id: asset_id,
-                change: amount as i64,
+        let receipt = try!(self.submit_json(MessagePayload::AmendOrder {
+            market_id: market_id,
+            order_id: order_id,
+            user_id: Some(user_id),
+            price: try!(decimal_from_json(desc, "price", 2)),
+            size: try!(decimal_from_json(desc, "size", 3)),
+        }));
+
+        let mut response = BTreeMap::new();
+        response.insert("sequence".to_string(), receipt.sequence.to_json());
+        response.insert("order_id".to_string(), order_id.to_json());
+
+        match receipt.report {
+            Report::OrderReduced(order) => {
+                response.insert("remaining".to_string(), order.remaining.to_json());
+                response.insert("kept_priority".to_string(), true.to_json());
+            },
+            Report::OrderReplaced { report, .. } => {
+                response.insert("kept_priority".to_string(), false.to_json());
+
+                if let Report::OrderCreated { order, trades, resting, .. } = *report {
+                    response.insert("remaining".to_string(), order.remaining.to_json());
+                    response.insert("resting".to_string(), resting.to_json());
+                    response.insert("trades".to_string(), Json::Array(trades.iter().map(trade_to_json).collect()));
+                }
             },
+            _ => {},
+        }
+
+        Ok(Json::Object(response))
+    }
+
+    fn handle_adjust_balance(&mut self, params: &Vec<Json>) -> Result<Json, Json> {
+        let desc = try!(param(params, 0));
+        let amount = try!(try!(decimal_from_json(desc, "amount", 10)).ok_or_else(|| invalid_request("amount")));
+        let user_id = 1; // TODO
+        let asset_id = match desc.get("asset").and_then(|x| x.as_string()) {
+            Some("BTC") => 1,
+            Some(_) => 2,
+            None => return Err(invalid_request("asset")),
         };
 
-        self.engine_tx.send(message).unwrap();
+        let receipt = try!(self.submit_json(MessagePayload::AdjustBalance {
+            user_id: user_id,
+            asset_id: asset_id,
+            change: amount as i64,
+        }));
 
-        let response = BTreeMap::new();
+        let mut response = BTreeMap::new();
+        response.insert("sequence".to_string(), receipt.sequence.to_json());
+
+        if let Report::BalanceAdjusted { balance, .. } = receipt.report {
+            response.insert("balance".to_string(), balance.to_json());
+        }
 
         Ok(Json::Object(response))
     }
 
+    // Requests that cannot be read are answered with an error, without an id
+    // when they have none
     fn handle_json_message(&mut self, message: BTreeMap<String, Json>) {
-        let method = message.get("method").unwrap().as_string().unwrap();
-        let params = message.get("params").unwrap().as_array().unwrap();
-        let id = message.get("id").unwrap().as_u64().unwrap();
-
-        let response = match method {
-            "createOrder" => self.handle_create_order(params),
-            "adjustBalance" => self.handle_adjust_balance(params),
-            "cancelOrder" => self.handle_cancel_order(params),
-            _ => unimplemented!(),
+        let method = message.get("method").and_then(|x| x.as_string());
+        let params = message.get("params").and_then(|x| x.as_array());
+        let id = message.get("id").and_then(|x| x.as_u64());
+
+        let response = match (method, params) {
+            (Some("createOrder"), Some(params)) => self.handle_create_order(params),
+            (Some("adjustBalance"), Some(params)) => self.handle_adjust_balance(params),
+            (Some("cancelOrder"), Some(params)) => self.handle_cancel_order(params),
+            (Some("createOcoOrders"), Some(params)) => self.handle_create_oco_orders(params),
+            (Some("amendOrder"), Some(params)) => self.handle_amend_order(params),
+            (Some("cancelAllOrders"), Some(params)) => self.handle_cancel_all_orders(params),
+            (Some(_), Some(_)) | (None, _) => Err(invalid_request("method")),
+            (Some(_), None) => Err(invalid_request("params")),
         };
 
         let response = match response {
@@ -113,7 +483,7 @@ impl SuezServerReceiver {
             Err(err) => {
                 let mut response = BTreeMap::new();
                 response.insert("id".to_string(), id.to_json());
-                // response.insert("error".to_string(), err.to_json());
+                response.insert("error".to_string(), err.to_json());
                 response
             },
         };
@@ -132,7 +502,11 @@ impl SuezServerReceiver {
             websocket::message::Type::Text => {
                 // println!("incoming: {:?}", str::from_utf8(&*message.payload).unwrap());
                 // self.send_tx.send(str::from_utf8(&*message.payload).unwrap().to_string()).unwrap();
-                self.handle_json_message(Json::from_str(str::from_utf8(&*message.payload).unwrap()).unwrap().as_object().unwrap().to_owned());
+                let message = str::from_utf8(&*message.payload).ok()
+                    .and_then(|x| Json::from_str(x).ok())
+                    .and_then(|x| x.as_object().cloned());
+
+                self.handle_json_message(message.unwrap_or_else(BTreeMap::new));
             },
             _ => unimplemented!(),
         };
@@ -147,17 +521,21 @@ impl SuezServerReceiver {
 
 pub struct SuezServer {
     balances: Balances,
-    engine_channel: mpsc::Sender<Message>,
+    engine_channel: mpsc::Sender<Request>,
     senders: Vec<mpsc::Sender<String>>,
 }
 
 impl SuezServer {
     pub fn new() -> SuezServer {
-        let mut balances = Balances::new(Config::hardcoded());
+        let config = Config::hardcoded();
+        let mut balances = Balances::new(config.clone());
         balances.adjust_balance(1, 1, 10000000000000000);
         balances.adjust_balance(1, 2, 10000000000000000);
 
-        let engine_channel = SuezEngine::<JsonJournalWriter>::start(balances.clone());
+        let engine_channel = match SuezEngine::<JsonJournalWriter>::start(config, balances.clone()) {
+            Ok(engine_channel) => engine_channel,
+            Err(err) => panic!("engine could not be started: {:?}", err),
+        };
         println!("engine created");
 
         SuezServer {
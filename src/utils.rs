@@ -10,6 +10,10 @@ pub type OrderPrice = u64;
 pub type MarketId = u32;
 pub type AssetId = u32;
 pub type UserId = u32;
+// Fee rates are in millionths, so 2500 is 0.25%. Negative rates are rebates.
+pub type FeeRate = i64;
+
+pub const FEE_RATE_SCALE: i64 = 1000000;
 
 #[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum OrderSide {
@@ -17,6 +21,150 @@ pub enum OrderSide {
     Sell,
 }
 
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OrderType {
+    Limit,
+    // Takes liquidity at any price and never rests
+    Market,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    GoodTillCanceled,
+    // Fill what is possible right away and cancel the rest
+    ImmediateOrCancel,
+    // Fill the whole order right away or nothing at all
+    FillOrKill,
+    // Rest until canceled or the timestamp (seconds since the epoch) passes
+    GoodTillDate(u64),
+}
+
+// What to do with a post only order that would take liquidity
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PostOnly {
+    Reject,
+    // Reprice to the best price that does not cross the spread
+    Slide,
+}
+
+// What to do when an order would match another order of the same user
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    // Cancel the rest of the incoming order
+    CancelNewest,
+    // Cancel the resting order and keep matching
+    CancelOldest,
+    CancelBoth,
+    // Reduce both orders by the size that would have traded, canceling
+    // whichever is left with nothing
+    DecrementAndCancel,
+}
+
+// What a market accepts
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MarketStatus {
+    Open,
+    // Only orders that rest without taking liquidity are accepted
+    PostOnly,
+    // Orders may be canceled or reduced but not placed
+    CancelOnly,
+    // Only operators may cancel orders
+    Halted,
+}
+
+// The price a pegged order follows. The primary side is the order's own side
+// of the book and the opposite side is the other.
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PegReference {
+    Primary,
+    Opposite,
+    Mid,
+}
+
+// Prices an order at its reference plus the offset, rounded to the tick away
+// from the opposite side. Buys are never priced above the cap and sells never
+// below it.
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset: i64,
+    pub cap: Option<OrderPrice>,
+}
+
+// How far the trigger of a trailing stop follows behind the price
+#[derive(RustcEncodable, RustcDecodable, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TrailingOffset {
+    Absolute(OrderPrice),
+    // In hundredths of a percent, so 150 is 1.5%
+    Percentage(u64),
+}
+
+impl TrailingOffset {
+    // The offset at a price, rounded down
+    pub fn at(&self, price: OrderPrice) -> OrderPrice {
+        match *self {
+            TrailingOffset::Absolute(offset) => offset,
+            TrailingOffset::Percentage(rate) => percentage_of(price, rate),
+        }
+    }
+}
+
+// A rate in hundredths of a percent of a price, rounded down
+pub fn percentage_of(price: OrderPrice, rate: u64) -> OrderPrice {
+    // Split the price to avoid overflowing on large prices
+    price / 10000 * rate + price % 10000 * rate / 10000
+}
+
+// What a price band is centered on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PriceReference {
+    // The last trade price, or the mid price before the first trade
+    LastTrade,
+    // Halfway between the best bid and ask
+    Mid,
+}
+
+// Limit orders priced further than the rate, in hundredths of a percent, from
+// the reference price are rejected, and market orders stop trading there
+#[derive(Clone)]
+pub struct PriceBand {
+    pub rate: u64,
+    pub reference: PriceReference,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CircuitBreakerAction {
+    Halt,
+    Auction,
+}
+
+// Trips when trade prices within the window, in seconds, move apart by more
+// than the rate in hundredths of a percent of the lowest
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    pub rate: u64,
+    pub window: u64,
+    pub action: CircuitBreakerAction,
+}
+
+// Shares a taker's size among the orders resting at a price in proportion to
+// their size. The oldest order may first be filled ahead of the others. Shares
+// below the minimum allocation are dropped and what is left over after
+// rounding down goes to the orders in time priority.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProRata {
+    pub top_order: bool,
+    pub min_allocation: OrderSize,
+}
+
+// How a market allocates takers among the orders resting at the best price
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Matching {
+    // Oldest first
+    Fifo,
+    ProRata(ProRata),
+}
+
 #[derive(Clone)]
 pub struct Market {
     pub id: MarketId,
@@ -25,8 +173,37 @@ pub struct Market {
     pub size_precision: u32,
     pub base_asset_id: AssetId,
     pub quote_asset_id: AssetId,
+    pub maker_fee: FeeRate,
+    pub taker_fee: FeeRate,
+    // Ordered by ascending volume. Users below the first tier pay the
+    // market's own rates.
+    pub fee_tiers: Vec<FeeTier>,
+    pub status: MarketStatus,
+    // Prices must be multiples of the tick size and sizes multiples of the
+    // lot size, both in the market's precision
+    pub tick_size: OrderPrice,
+    pub lot_size: OrderSize,
+    pub min_size: OrderSize,
+    pub max_size: OrderSize,
+    // The least a limit order may be worth at its price, or a market buy may
+    // be given to spend, in the quote asset
+    pub min_notional: u64,
+    pub price_band: Option<PriceBand>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub matching: Matching,
 }
 
+// Rates for users whose trailing volume in the market is at least min_volume
+#[derive(Clone)]
+pub struct FeeTier {
+    pub min_volume: i64,
+    pub maker_fee: FeeRate,
+    pub taker_fee: FeeRate,
+}
+
+// How far back volume counts towards fee tiers
+pub const FEE_TIER_WINDOW_DAYS: u64 = 30;
+
 #[derive(Clone)]
 pub struct Asset {
     pub id: AssetId,
@@ -38,6 +215,8 @@ pub struct Asset {
 pub struct Config {
     pub markets: HashMap<MarketId, Market>,
     pub assets: HashMap<AssetId, Asset>,
+    // The account fees are collected into and rebates are paid from
+    pub fee_user_id: UserId,
 }
 
 impl Config {
@@ -62,11 +241,24 @@ impl Config {
             size_precision: 3,
             base_asset_id: 1,
             quote_asset_id: 2,
+            maker_fee: 0,
+            taker_fee: 0,
+            fee_tiers: vec![],
+            status: MarketStatus::Open,
+            tick_size: 1,
+            lot_size: 1,
+            min_size: 1,
+            max_size: u64::max_value(),
+            min_notional: 0,
+            price_band: None,
+            circuit_breaker: None,
+            matching: Matching::Fifo,
         });
 
         Config {
             assets: assets,
             markets: markets,
+            fee_user_id: 0,
         }
     }
 }
@@ -82,6 +274,26 @@ pub struct Order {
     pub price: OrderPrice,
     pub size: OrderSize,
     pub remaining: OrderSize,
+    pub order_type: OrderType,
+    // The most quote a market buy may spend, reduced as it fills. A market
+    // buy with funds and a size of zero is limited by funds alone.
+    pub funds: Option<u64>,
+    pub time_in_force: TimeInForce,
+    pub post_only: Option<PostOnly>,
+    // Self trades are allowed when None
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    // Held outside the book until the last trade price reaches the stop
+    // price, at or above it for buys and at or below it for sells
+    pub stop_price: Option<OrderPrice>,
+    // Makes the stop price follow the best price traded since the order was
+    // placed, the highest for sells and the lowest for buys
+    pub trailing_offset: Option<TrailingOffset>,
+    // Iceberg orders show at most this much of their remaining size in the
+    // book, and show the next slice once it is consumed
+    pub display_size: Option<OrderSize>,
+    // Pegged orders are priced by the book rather than by the user, and are
+    // moved to the back of their new price whenever it changes
+    pub peg: Option<Peg>,
 }
 
 impl Order {
@@ -94,12 +306,47 @@ impl Order {
             price: price,
             size: size,
             remaining: size,
+            order_type: OrderType::Limit,
+            funds: None,
+            time_in_force: TimeInForce::GoodTillCanceled,
+            post_only: None,
+            self_trade_prevention: None,
+            stop_price: None,
+            trailing_offset: None,
+            display_size: None,
+            peg: None,
+        }
+    }
+
+    pub fn market(id: OrderId, user_id: UserId, market_id: MarketId, side: OrderSide, size: OrderSize, funds: Option<u64>) -> Order {
+        Order {
+            order_type: OrderType::Market,
+            funds: funds,
+            ..Order::new(id, user_id, market_id, side, 0, size)
+        }
+    }
+
+    // Whether the order is still bounded by its remaining size
+    pub fn is_size_limited(&self) -> bool {
+        !(self.order_type == OrderType::Market && self.size == 0)
+    }
+
+    // Whether what is left after matching may rest in the book
+    pub fn can_rest(&self) -> bool {
+        if self.order_type != OrderType::Limit {
+            return false;
+        }
+
+        match self.time_in_force {
+            TimeInForce::GoodTillCanceled | TimeInForce::GoodTillDate(_) => true,
+            TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill => false,
         }
     }
 }
 
 // A trade is a match between a bid and an ask
 #[derive(RustcEncodable, RustcDecodable, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Trade {
     pub market_id: MarketId,
     pub price: OrderPrice,
@@ -110,6 +357,15 @@ pub struct Trade {
     pub taker_user_id: UserId,
     // Which side the maker was on
     pub side: OrderSide,
+    // The limit price of the taker, which its hold was reserved at. Zero for
+    // market orders.
+    pub taker_price: OrderPrice,
+    // Fees charged during settlement, in the asset each side received
+    pub maker_fee: i64,
+    pub taker_fee: i64,
+    // Matched when an auction uncrossed. Neither side took liquidity from the
+    // other, so both pay the taker fee.
+    pub auction: bool,
 }
 
 impl Trade {
@@ -123,10 +379,20 @@ impl Trade {
             taker_user_id: taker.user_id,
             side: maker.side,
             market_id: maker.market_id,
+            taker_price: taker.price,
+            maker_fee: 0,
+            taker_fee: 0,
+            auction: false,
         }
     }
 }
 
+// The fee for an amount at the given rate, rounded toward zero
+pub fn fee_for(amount: i64, rate: FeeRate) -> i64 {
+    // Split the amount to avoid overflowing on large totals
+    amount / FEE_RATE_SCALE * rate + amount % FEE_RATE_SCALE * rate / FEE_RATE_SCALE
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ParseDecimalError {
     BadIntegerPart,
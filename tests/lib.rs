@@ -10,10 +10,9 @@ use suez::messages::*;
 
 #[test]
 fn it_passes_scenario_1() {
-    let balances = Balances::new(Config::hardcoded());
-    let book = Book::new();
+    let config = Config::hardcoded();
+    let balances = Balances::new(config.clone());
 
-    // TODO: Self match protection
     // TODO: Tests for engine validation
     // TODO: Should probably do messages with remaining == 0 and have it set after
     // TODO: Remove precision everywhere. 5 for price, 5 for size. 5 + 5 = 10 for totals
@@ -24,52 +23,52 @@ fn it_passes_scenario_1() {
     const QUOTE_ASSET_ID: AssetId = 2;
     const MARKET_ID: MarketId = 1;
 
-    let mut engine = SuezEngine::<JsonJournalWriter> {
-        book: book,
-        sequencer: Sequencer { sequence: 0 },
-        journaler: JsonJournalWriter::new("journal.json").unwrap(),
-        balances: balances,
-    };
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal.json").unwrap(), balances).unwrap();
 
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::AdjustBalance {
             user_id: ALICE_USER_ID,
             asset_id: BASE_ASSET_ID,
             change: 1000,
         },
-    });
+    }).unwrap();
 
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::AdjustBalance {
             user_id: BOB_USER_ID,
             asset_id: BASE_ASSET_ID,
             change: 1000,
         },
-    });
+    }).unwrap();
 
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::AdjustBalance {
             user_id: BOB_USER_ID,
             asset_id: QUOTE_ASSET_ID,
             change: 1,
         },
-    });
+    }).unwrap();
 
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::AdjustBalance {
             user_id: CAROL_USER_ID,
             asset_id: QUOTE_ASSET_ID,
             change: 35000,
         },
-    });
+    }).unwrap();
 
     // Alice: Sell 200 @ 100 (20 000)
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::CreateOrder(Order {
             id: 1,
             market_id: MARKET_ID,
@@ -78,12 +77,22 @@ fn it_passes_scenario_1() {
             size: 200,
             side: OrderSide::Sell,
             remaining: 200,
+            order_type: OrderType::Limit,
+            funds: None,
+            time_in_force: TimeInForce::GoodTillCanceled,
+            post_only: None,
+            self_trade_prevention: None,
+            stop_price: None,
+            trailing_offset: None,
+            display_size: None,
+            peg: None,
         }),
-    });
+    }).unwrap();
 
     // Bob: Sell 150 @ 100 (15 000)
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::CreateOrder(Order {
             id: 2,
             market_id: MARKET_ID,
@@ -92,12 +101,22 @@ fn it_passes_scenario_1() {
             size: 150,
             side: OrderSide::Sell,
             remaining: 150,
+            order_type: OrderType::Limit,
+            funds: None,
+            time_in_force: TimeInForce::GoodTillCanceled,
+            post_only: None,
+            self_trade_prevention: None,
+            stop_price: None,
+            trailing_offset: None,
+            display_size: None,
+            peg: None,
         }),
-    });
+    }).unwrap();
 
     // Carol: Buy 300 @ 110 (33 000)
     engine.process_message(Message {
         sequence: 0,
+        timestamp: 0,
         payload: MessagePayload::CreateOrder(Order {
             id: 3,
             market_id: MARKET_ID,
@@ -106,15 +125,24 @@ fn it_passes_scenario_1() {
             size: 300,
             side: OrderSide::Buy,
             remaining: 300,
+            order_type: OrderType::Limit,
+            funds: None,
+            time_in_force: TimeInForce::GoodTillCanceled,
+            post_only: None,
+            self_trade_prevention: None,
+            stop_price: None,
+            trailing_offset: None,
+            display_size: None,
+            peg: None,
         }),
-    });
+    }).unwrap();
 
     // Confirm book
-    assert_eq!(engine.book.bids.len(), 0);
-    assert_eq!(engine.book.asks.len(), 1);
-    assert_eq!(engine.book.asks[0].price, 100);
-    assert_eq!(engine.book.asks[0].size, 150);
-    assert_eq!(engine.book.asks[0].remaining, 50);
+    assert_eq!(engine.books[&MARKET_ID].bids.len(), 0);
+    assert_eq!(engine.books[&MARKET_ID].asks.len(), 1);
+    assert_eq!(engine.books[&MARKET_ID].asks[0].price, 100);
+    assert_eq!(engine.books[&MARKET_ID].asks[0].size, 150);
+    assert_eq!(engine.books[&MARKET_ID].asks[0].remaining, 50);
 
     // Confirm balances
     // let balances = engine.balances.balances.read().unwrap();
@@ -123,17 +151,1474 @@ fn it_passes_scenario_1() {
     assert_eq!(engine.balances.get_balance(BOB_USER_ID, BASE_ASSET_ID), 1000 - 150);
     assert_eq!(engine.balances.get_balance(BOB_USER_ID, QUOTE_ASSET_ID), 1 + 100 * 100);
     assert_eq!(engine.balances.get_balance(CAROL_USER_ID, BASE_ASSET_ID), 300);
-    assert_eq!(engine.balances.get_balance(CAROL_USER_ID, QUOTE_ASSET_ID), 35000 - 33000);
+    // Carol bid 110 but paid 100, the difference is not kept on hold
+    assert_eq!(engine.balances.get_balance(CAROL_USER_ID, QUOTE_ASSET_ID), 35000 - 300 * 100);
+    assert_eq!(engine.balances.get_held(CAROL_USER_ID, QUOTE_ASSET_ID), 0);
+    assert_eq!(engine.balances.get_held(ALICE_USER_ID, BASE_ASSET_ID), 0);
+    assert_eq!(engine.balances.get_held(BOB_USER_ID, BASE_ASSET_ID), 50);
+
+    // Alice: Cancel Bob's #2, which is not hers to cancel
+    let outcome = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 2, user_id: Some(ALICE_USER_ID) },
+    });
+
+    assert_eq!(outcome, Err(EngineError::NotOrderOwner(2)));
+    assert_eq!(engine.books[&MARKET_ID].asks.len(), 1);
 
     // Bob: Cancel remainder of #2
-    // TODO: Test to make sure you cant cancel other peoples orders
-    // TODO: Make sure orders exist before cancel
     engine.process_message(Message {
         sequence: 0,
-        payload: MessagePayload::CancelOrder { order_id: 2 },
-    });
+        timestamp: 0,
+        payload: MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 2, user_id: Some(BOB_USER_ID) },
+    }).unwrap();
 
-    assert_eq!(engine.book.asks.len(), 0);
+    assert_eq!(engine.books[&MARKET_ID].asks.len(), 0);
 
     assert_eq!(engine.balances.get_balance(BOB_USER_ID, BASE_ASSET_ID), 1000 - 150 + 50);
+    assert_eq!(engine.balances.get_held(BOB_USER_ID, BASE_ASSET_ID), 0);
+}
+
+#[test]
+fn it_keeps_a_separate_book_per_market() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BTC_ASSET_ID: AssetId = 1;
+    const USD_ASSET_ID: AssetId = 2;
+    const ETH_ASSET_ID: AssetId = 3;
+    const BTCUSD_MARKET_ID: MarketId = 1;
+    const ETHBTC_MARKET_ID: MarketId = 2;
+
+    let mut config = Config::hardcoded();
+    config.assets.insert(ETH_ASSET_ID, Asset {
+        id: ETH_ASSET_ID,
+        name: "ETH".to_string(),
+        precision: 8,
+    });
+    config.markets.insert(ETHBTC_MARKET_ID, Market {
+        id: ETHBTC_MARKET_ID,
+        name: "ETHBTC".to_string(),
+        price_precision: 5,
+        size_precision: 3,
+        base_asset_id: ETH_ASSET_ID,
+        quote_asset_id: BTC_ASSET_ID,
+        maker_fee: 0,
+        taker_fee: 0,
+        fee_tiers: vec![],
+        status: MarketStatus::Open,
+        tick_size: 1,
+        lot_size: 1,
+        min_size: 1,
+        max_size: u64::max_value(),
+        min_notional: 0,
+        price_band: None,
+        circuit_breaker: None,
+        matching: Matching::Fifo,
+    });
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BTC_ASSET_ID, 1000);
+    balances.adjust_balance(BOB_USER_ID, BTC_ASSET_ID, 1000);
+    balances.adjust_balance(BOB_USER_ID, USD_ASSET_ID, 100000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-markets.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 10 BTC @ 100 USD
+    engine.process_message(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(1, ALICE_USER_ID, BTCUSD_MARKET_ID, OrderSide::Sell, 100, 10)),
+    }).unwrap();
+
+    // Bob: Buy 10 ETH @ 100 BTC, which must not match Alice's BTCUSD ask
+    engine.process_message(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(2, BOB_USER_ID, ETHBTC_MARKET_ID, OrderSide::Buy, 100, 10)),
+    }).unwrap();
+
+    assert_eq!(engine.book(BTCUSD_MARKET_ID).unwrap().asks[0].remaining, 10);
+    assert_eq!(engine.book(ETHBTC_MARKET_ID).unwrap().bids[0].remaining, 10);
+
+    // Bob: Buy 5 BTC @ 100 USD
+    engine.process_message(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(3, BOB_USER_ID, BTCUSD_MARKET_ID, OrderSide::Buy, 100, 5)),
+    }).unwrap();
+
+    assert_eq!(engine.book(BTCUSD_MARKET_ID).unwrap().asks[0].remaining, 5);
+    assert_eq!(engine.book(ETHBTC_MARKET_ID).unwrap().bids[0].remaining, 10);
+
+    // Orders for unknown markets are rejected
+    let message = Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(4, BOB_USER_ID, 99, OrderSide::Buy, 100, 1)),
+    };
+    assert_eq!(engine.validate(&message), Err(EngineError::UnknownMarket(99)));
+}
+
+#[test]
+fn it_reports_outcome_of_submitted_messages() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let balances = Balances::new(config.clone());
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-outcome.json").unwrap(), balances).unwrap();
+
+    let receipt = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::AdjustBalance {
+            user_id: ALICE_USER_ID,
+            asset_id: BASE_ASSET_ID,
+            change: 100,
+        },
+    }).unwrap();
+
+    assert_eq!(receipt.sequence, 1);
+    assert_eq!(receipt.report, Report::BalanceAdjusted {
+        user_id: ALICE_USER_ID,
+        asset_id: BASE_ASSET_ID,
+        balance: 100,
+    });
+
+    // Bob cannot afford to buy and is rejected without consuming a sequence
+    let outcome = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(1, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10)),
+    });
+
+    assert_eq!(outcome, Err(EngineError::InsufficientFunds));
+    assert_eq!(engine.sequencer.sequence, 1);
+
+    let receipt = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10)),
+    }).unwrap();
+
+    assert_eq!(receipt.sequence, 2);
+
+    match receipt.report {
+        Report::OrderCreated { order, trades, .. } => {
+            assert_eq!(order.id, 2);
+            assert_eq!(order.remaining, 10);
+            assert_eq!(trades.len(), 0);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    let receipt = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 2, user_id: None },
+    }).unwrap();
+
+    assert_eq!(receipt.sequence, 3);
+
+    match receipt.report {
+        Report::OrderCanceled(order) => assert_eq!(order.remaining, 10),
+        _ => panic!("incorrect report"),
+    }
+}
+
+#[test]
+fn it_rejects_bad_client_input_without_panicking() {
+    const ALICE_USER_ID: UserId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-errors.json").unwrap(), balances).unwrap();
+
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID) };
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }), Err(EngineError::UnknownOrder(1)));
+
+    // Applying directly, as replay does, must not panic either
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID) };
+    let receipt = engine.apply_message(&Message { sequence: 1, timestamp: 0, payload: cancel });
+    assert_eq!(receipt.report, Report::Rejected(EngineError::UnknownOrder(1)));
+
+    let zero_price = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 0, 10);
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(zero_price) }),
+        Err(EngineError::InvalidPrice(0)));
+
+    let zero_size = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 10, 0);
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(zero_size) }),
+        Err(EngineError::InvalidSize(0)));
+
+    let overflowing = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 1 << 40, 1 << 40);
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(overflowing) }),
+        Err(EngineError::InvalidSize(1 << 40)));
+
+    let order = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 10, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(order) }).unwrap();
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(order) }),
+        Err(EngineError::DuplicateOrderId(1)));
+
+    // Validated at the current time, so a past expiry is never journaled
+    let sequence = engine.sequencer.sequence;
+    let mut expired = Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 10, 10);
+    expired.time_in_force = TimeInForce::GoodTillDate(1000);
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(expired) }),
+        Err(EngineError::InvalidTimeInForce(TimeInForce::GoodTillDate(1000))));
+    assert_eq!(engine.sequencer.sequence, sequence);
+
+    // What happened before a message failed to apply is still reported
+    let mut expiring = Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 10, 10);
+    expiring.time_in_force = TimeInForce::GoodTillDate(20);
+    engine.submit(Message { sequence: 0, timestamp: 10, payload: MessagePayload::CreateOrder(expiring) }).unwrap();
+
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 9, user_id: Some(ALICE_USER_ID) };
+    let receipt = engine.process_message(Message { sequence: 0, timestamp: 20, payload: cancel }).unwrap();
+    assert_eq!(receipt.report, Report::Rejected(EngineError::UnknownOrder(9)));
+    assert_eq!(receipt.expired.iter().map(|x| x.id).collect::<Vec<_>>(), vec![3]);
+}
+
+#[test]
+fn it_charges_pinned_fee_tier() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let mut config = Config::hardcoded();
+
+    {
+        let market = config.markets.get_mut(&MARKET_ID).unwrap();
+        market.taker_fee = 10000;
+        market.fee_tiers = vec![FeeTier { min_volume: 1000000, maker_fee: 0, taker_fee: 5000 }];
+    }
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 1000);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 100000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-fee-tiers.json").unwrap(), balances).unwrap();
+
+    let pin = MessagePayload::SetFeeTier { user_id: BOB_USER_ID, market_id: MARKET_ID, tier: Some(1) };
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: pin }), Err(EngineError::UnknownFeeTier(1)));
+
+    let pin = MessagePayload::SetFeeTier { user_id: BOB_USER_ID, market_id: MARKET_ID, tier: Some(0) };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: pin }).unwrap();
+
+    let ask = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 1000);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    let bid = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 1000);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { trades, .. } => assert_eq!(trades[0].taker_fee, 5),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get_balance(BOB_USER_ID, BASE_ASSET_ID), 1000 - 5);
+}
+
+#[test]
+fn it_reserves_and_releases_funds_for_market_orders() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 30);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-market-orders.json").unwrap(), balances).unwrap();
+
+    engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10)),
+    }).unwrap();
+
+    engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 200, 10)),
+    }).unwrap();
+
+    // Bob: Buy 15 at market, costing 10 * 100 + 5 * 200
+    let receipt = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::market(3, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 15, None)),
+    }).unwrap();
+
+    // The balance it may spend is not reported
+    match receipt.report {
+        Report::OrderCreated { order, .. } => assert_eq!(order.funds, None),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get_balance(BOB_USER_ID, QUOTE_ASSET_ID), 10000 - 2000);
+    assert_eq!(engine.balances.get_held(BOB_USER_ID, QUOTE_ASSET_ID), 0);
+    assert_eq!(engine.balances.get_balance(BOB_USER_ID, BASE_ASSET_ID), 15);
+
+    // Bob: Buy with 1500 of funds, taking the last 5 and leaving 500
+    let receipt = engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::market(4, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 0, Some(1500))),
+    }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { order, trades, .. } => {
+            assert_eq!(trades.len(), 1);
+            assert_eq!(order.funds, Some(500));
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get_balance(BOB_USER_ID, QUOTE_ASSET_ID), 10000 - 2000 - 1000);
+    assert_eq!(engine.balances.get_held(BOB_USER_ID, QUOTE_ASSET_ID), 0);
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks.len(), 0);
+
+    // Alice: Sell 10 at market into an empty book never rests
+    engine.submit(Message {
+        sequence: 0,
+        timestamp: 0,
+        payload: MessagePayload::CreateOrder(Order::market(5, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 10, None)),
+    }).unwrap();
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids.len(), 0);
+    assert_eq!(engine.balances.get_balance(ALICE_USER_ID, BASE_ASSET_ID), 10);
+    assert_eq!(engine.balances.get_held(ALICE_USER_ID, BASE_ASSET_ID), 0);
+
+    let sell_with_funds = Order::market(6, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 10, Some(100));
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(sell_with_funds) }),
+        Err(EngineError::InvalidFunds(100)));
+}
+
+#[test]
+fn it_releases_funds_of_orders_that_do_not_rest() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-time-in-force.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 10 @ 100 until 1000
+    let mut ask = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10);
+    ask.time_in_force = TimeInForce::GoodTillDate(1000);
+    engine.submit(Message { sequence: 0, timestamp: 500, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    assert_eq!(engine.balances.get_held(ALICE_USER_ID, BASE_ASSET_ID), 10);
+
+    // Bob: Buy 15 @ 100 immediate or cancel, filling 10
+    let mut bid = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 15);
+    bid.time_in_force = TimeInForce::ImmediateOrCancel;
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 600, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { order, resting, .. } => {
+            assert_eq!(order.remaining, 5);
+            assert!(!resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get_balance(BOB_USER_ID, QUOTE_ASSET_ID), 10000 - 1000);
+    assert_eq!(engine.balances.get_held(BOB_USER_ID, QUOTE_ASSET_ID), 0);
+
+    // Alice: Sell 10 @ 100 until 1000 again, which expires by the next message
+    ask.id = 3;
+    engine.submit(Message { sequence: 0, timestamp: 700, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    let receipt = engine.submit(Message {
+        sequence: 0,
+        timestamp: 1000,
+        payload: MessagePayload::AdjustBalance { user_id: BOB_USER_ID, asset_id: QUOTE_ASSET_ID, change: 1 },
+    }).unwrap();
+
+    assert_eq!(receipt.expired.len(), 1);
+    assert_eq!(receipt.expired[0].id, 3);
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks.len(), 0);
+    assert_eq!(engine.balances.get_balance(ALICE_USER_ID, BASE_ASSET_ID), 100 - 10);
+    assert_eq!(engine.balances.get_held(ALICE_USER_ID, BASE_ASSET_ID), 0);
+
+    // Expiry must be in the future
+    ask.id = 4;
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 1000, payload: MessagePayload::CreateOrder(ask) }),
+        Err(EngineError::InvalidTimeInForce(TimeInForce::GoodTillDate(1000))));
+}
+
+#[test]
+fn it_rejects_or_slides_post_only_orders() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-post-only.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 10 @ 100
+    let ask = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    // Bob: Buy 10 @ 105 post only, which would take
+    let mut bid = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 105, 10);
+    bid.post_only = Some(PostOnly::Reject);
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }),
+        Err(EngineError::PostOnlyWouldTake));
+
+    // Bob: Buy 10 @ 105 sliding to 99, and funds are held at 99
+    bid.post_only = Some(PostOnly::Slide);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { order, trades, resting, .. } => {
+            assert_eq!(order.price, 99);
+            assert_eq!(trades.len(), 0);
+            assert!(resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get_held(BOB_USER_ID, QUOTE_ASSET_ID), 10 * 99);
+    assert_eq!(engine.book(MARKET_ID).unwrap().best_bid(), Some(99));
+}
+
+#[test]
+fn it_releases_funds_of_orders_canceled_by_self_trade_prevention() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 100);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-self-trade.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 10 @ 100
+    let ask = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    // Alice: Buy 4 @ 100, decrementing both
+    let mut bid = Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 4);
+    bid.self_trade_prevention = Some(SelfTradePrevention::DecrementAndCancel);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { trades, canceled, .. } => {
+            assert_eq!(trades.len(), 0);
+            assert_eq!(canceled.len(), 2);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 100 - 6, held: 6 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000, held: 0 });
+
+    // Alice: Buy 10 @ 100, canceling the resting ask and resting itself
+    let mut bid = Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    bid.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 100, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 1000, held: 1000 });
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks.len(), 0);
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids.len(), 1);
+
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 3, user_id: Some(ALICE_USER_ID) };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    // Alice: Sell 10 @ 100, Bob: Sell 10 @ 100
+    let ask = Order::new(4, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+    let ask = Order::new(5, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    // Alice: Buy for 1500 at market, decrementing her own ask and buying 5 from Bob
+    let mut bid = Order::market(6, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 0, Some(1500));
+    bid.self_trade_prevention = Some(SelfTradePrevention::DecrementAndCancel);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    // What the decremented size would have cost is released with the rest
+    assert_eq!(engine.balances.get_held(ALICE_USER_ID, QUOTE_ASSET_ID), 0);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 500, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 105, held: 0 });
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks[0].remaining, 5);
+}
+
+#[test]
+fn it_triggers_stop_orders_after_trades() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const CHARLIE_USER_ID: UserId = 3;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(CHARLIE_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-stop.json").unwrap(), balances).unwrap();
+
+    // Bob: Sell 5 @ 100 and 5 @ 110
+    let ask = Order::new(1, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 100, 5);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+    let ask = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 110, 5);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    // Alice: Buy 5 @ 120 once the last trade price reaches 100
+    let mut stop = Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 120, 5);
+    stop.stop_price = Some(100);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(stop) }).unwrap();
+
+    assert_eq!(receipt.report, Report::StopOrderPlaced(stop));
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 600, held: 600 });
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids.len(), 0);
+
+    // Charlie: Buy 5 @ 100, trading at 100 and triggering Alice's stop
+    let bid = Order::new(4, CHARLIE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 5);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    assert_eq!(receipt.triggered.len(), 1);
+
+    match receipt.triggered[0] {
+        Report::OrderCreated { ref order, ref trades, resting, .. } => {
+            assert_eq!(order.id, 3);
+            assert_eq!(order.remaining, 0);
+            assert_eq!(trades.len(), 1);
+            assert_eq!(trades[0].price, 110);
+            assert!(!resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    // The stop bought at 110 and was refunded the rest of its hold at 120
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 550, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 0 });
+
+    // A stop the last trade price has already reached is rejected
+    let mut stop = Order::new(5, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 120, 5);
+    stop.stop_price = Some(105);
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(stop) });
+
+    assert_eq!(outcome, Err(EngineError::InvalidStopPrice(105)));
+}
+
+#[test]
+fn it_trails_stop_orders_behind_traded_prices() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const CHARLIE_USER_ID: UserId = 3;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 10);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(CHARLIE_USER_ID, QUOTE_ASSET_ID, 100000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-trailing.json").unwrap(), balances).unwrap();
+
+    // Bob: Sell 10 @ 1000, Charlie: Buy 10 @ 1000
+    let ask = Order::new(1, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 1000, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+    let bid = Order::new(2, CHARLIE_USER_ID, MARKET_ID, OrderSide::Buy, 1000, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    // Alice: Sell 10 at market once the price falls 10% from its high
+    let mut stop = Order::market(3, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 10, None);
+    stop.trailing_offset = Some(TrailingOffset::Percentage(1000));
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(stop) }).unwrap();
+
+    match receipt.report {
+        Report::StopOrderPlaced(order) => assert_eq!(order.stop_price, Some(900)),
+        _ => panic!("incorrect report"),
+    }
+
+    // Bob: Sell 10 @ 1200, Charlie: Buy 5 @ 1200, moving the trigger up
+    let ask = Order::new(4, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 1200, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+    let bid = Order::new(5, CHARLIE_USER_ID, MARKET_ID, OrderSide::Buy, 1200, 5);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().get_order(3).unwrap().stop_price, Some(1080));
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 0, held: 10 });
+
+    // Charlie: Buy 10 @ 1050, Bob: Sell 5 @ 1050, trading below the trigger
+    let bid = Order::new(6, CHARLIE_USER_ID, MARKET_ID, OrderSide::Buy, 1050, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    let ask = Order::new(7, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 1050, 5);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    assert_eq!(receipt.triggered.len(), 1);
+
+    match receipt.triggered[0] {
+        Report::OrderCreated { ref order, ref trades, resting, .. } => {
+            assert_eq!(order.id, 3);
+            assert_eq!(trades.len(), 1);
+            assert_eq!((trades[0].price, trades[0].size), (1050, 5));
+            assert!(!resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    // What the stop could not sell is released
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 5250, held: 0 });
+}
+
+#[test]
+fn it_amends_orders_adjusting_holds_and_priority() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-amend.json").unwrap(), balances).unwrap();
+
+    // Alice then Bob: Buy 10 @ 100
+    let bid = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    let bid = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    // Alice reduces to 6 and stays first in line
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: None, size: Some(6) };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend }).unwrap();
+
+    match receipt.report {
+        Report::OrderReduced(order) => assert_eq!(order.remaining, 6),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[0].id, 1);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 600, held: 600 });
+
+    // Alice grows to 12, which costs her place behind Bob
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: None, size: Some(12) };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend }).unwrap();
+
+    match receipt.report {
+        Report::OrderReplaced { previous, .. } => assert_eq!(previous.remaining, 6),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[0].id, 2);
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[1].remaining, 12);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 1200, held: 1200 });
+
+    // Moving the price beyond what Alice can hold is rejected
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: Some(1000), size: None };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend });
+
+    assert_eq!(outcome, Err(EngineError::InsufficientFunds));
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[1].price, 100);
+
+    // Only Alice or an operator may amend her order
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(BOB_USER_ID), price: None, size: Some(8) };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend });
+
+    assert_eq!(outcome, Err(EngineError::NotOrderOwner(1)));
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[1].remaining, 12);
+
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: None, price: None, size: Some(8) };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: amend }).unwrap();
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[1].remaining, 8);
+}
+
+#[test]
+fn it_holds_and_releases_full_size_of_iceberg_orders() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 30);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-iceberg.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 25 @ 100, showing 10 at a time
+    let mut ask = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 25);
+    ask.display_size = Some(10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    // The hidden size is held as well
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 25 });
+
+    // Bob: Buy 15 @ 100, taking one slice and part of the next
+    let bid = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 15);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { ref trades, .. } => {
+            assert_eq!(trades.iter().map(|x| x.size).collect::<Vec<_>>(), vec![10, 5]);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().get_order(1).unwrap().remaining, 10);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 10 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 1500, held: 0 });
+
+    // Alice: Amend to 35 in total, which she cannot afford, then to 30
+    let amend = |size: OrderSize| MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: None, size: Some(size) };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend(35) });
+    assert_eq!(outcome, Err(EngineError::InsufficientFunds));
+
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: amend(30) }).unwrap();
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().get_order(1).unwrap().remaining, 15);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 0, held: 15 });
+
+    // Reducing releases the difference
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend(27) }).unwrap();
+
+    match receipt.report {
+        Report::OrderReduced(order) => assert_eq!((order.remaining, order.display_size), (12, Some(10))),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 3, held: 12 });
+
+    // Below the display size, the whole of what is left shows
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend(20) }).unwrap();
+
+    match receipt.report {
+        Report::OrderReduced(order) => assert_eq!((order.remaining, order.display_size), (5, Some(5))),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 10, held: 5 });
+
+    // Canceling releases what is left
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID) };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 15, held: 0 });
+}
+
+#[test]
+fn it_cancels_all_orders_of_a_user() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-cancel-all.json").unwrap(), balances).unwrap();
+
+    let orders = vec![
+        Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 90, 10),
+        Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 110, 10),
+        Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 80, 10),
+        Order::new(4, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 90, 10),
+    ];
+
+    for order in orders {
+        engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(order) }).unwrap();
+    }
+
+    // Alice: Cancel all buys
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: Some(MARKET_ID), side: Some(OrderSide::Buy), requested_by: Some(ALICE_USER_ID) };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    match receipt.report {
+        Report::OrdersCanceled(orders) => {
+            assert_eq!(orders.iter().map(|x| x.id).collect::<Vec<OrderId>>(), vec![1, 3]);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 90, held: 10 });
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids.len(), 1);
+
+    // Alice: Sell 10 @ 120, or at market once it trades at 80
+    let limit = Order::new(5, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 120, 10);
+    let mut stop = Order::market(6, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 10, None);
+    stop.stop_price = Some(80);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOcoOrders { limit: limit, stop: stop } }).unwrap();
+
+    // Alice: Cancel everything, including both legs of the pair
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: None, side: None, requested_by: Some(ALICE_USER_ID) };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    match receipt.report {
+        Report::OrdersCanceled(orders) => {
+            assert_eq!(orders.iter().map(|x| x.id).collect::<Vec<OrderId>>(), vec![2, 5, 6]);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert!(receipt.unlinked.is_empty());
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 100, held: 0 });
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks.len(), 0);
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[0].user_id, BOB_USER_ID);
+
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: Some(9), side: None, requested_by: Some(ALICE_USER_ID) };
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }), Err(EngineError::UnknownMarket(9)));
+
+    // Bob cannot cancel for Alice
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: None, side: None, requested_by: Some(BOB_USER_ID) };
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }), Err(EngineError::UserMismatch(BOB_USER_ID)));
+}
+
+#[test]
+fn it_cancels_all_orders_around_halted_markets() {
+    const ALICE_USER_ID: UserId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+    const HALTED_MARKET_ID: MarketId = 2;
+
+    let mut config = Config::hardcoded();
+    let mut market = config.markets[&MARKET_ID].clone();
+    market.id = HALTED_MARKET_ID;
+    config.markets.insert(HALTED_MARKET_ID, market);
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-cancel-halted.json").unwrap(), balances).unwrap();
+
+    for market_id in vec![MARKET_ID, HALTED_MARKET_ID] {
+        let bid = Order::new(1, ALICE_USER_ID, market_id, OrderSide::Buy, 100, 10);
+        engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    }
+
+    let status = MessagePayload::SetMarketStatus { market_id: HALTED_MARKET_ID, status: MarketStatus::Halted };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    // Alice: Cancel everything, which leaves the halted market alone
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: None, side: None, requested_by: Some(ALICE_USER_ID) };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    match receipt.report {
+        Report::OrdersCanceled(orders) => {
+            assert_eq!(orders.iter().map(|x| (x.market_id, x.id)).collect::<Vec<_>>(), vec![(MARKET_ID, 1)]);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: Some(HALTED_MARKET_ID), side: None, requested_by: Some(ALICE_USER_ID) };
+    assert_eq!(engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }), Err(EngineError::MarketNotOpen(HALTED_MARKET_ID)));
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 9000, held: 1000 });
+
+    // An operator cancels there too
+    let cancel = MessagePayload::CancelAllOrders { user_id: ALICE_USER_ID, market_id: None, side: None, requested_by: None };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    match receipt.report {
+        Report::OrdersCanceled(orders) => {
+            assert_eq!(orders.iter().map(|x| (x.market_id, x.id)).collect::<Vec<_>>(), vec![(HALTED_MARKET_ID, 1)]);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000, held: 0 });
+}
+
+#[test]
+fn it_cancels_other_leg_of_oco_orders() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 15);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 100);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 100000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-oco.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 10 @ 120 to take profit, or at market once it trades at 90
+    let limit = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 120, 10);
+    let mut stop = Order::market(2, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 10, None);
+    stop.stop_price = Some(90);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOcoOrders { limit: limit, stop: stop } }).unwrap();
+
+    // Both legs are covered by one hold
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 10 });
+
+    // Bob: Buy 4 @ 120, filling part of the limit leg
+    let bid = Order::new(3, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 120, 4);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    assert_eq!(receipt.unlinked.len(), 1);
+    assert_eq!(receipt.unlinked[0].id, 2);
+    assert!(engine.book(MARKET_ID).unwrap().get_order(2).is_none());
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 6 });
+
+    // Alice: Sell 5 @ 130 or at market once it trades at 100
+    let limit = Order::new(4, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 130, 5);
+    let mut stop = Order::market(5, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 5, None);
+    stop.stop_price = Some(100);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOcoOrders { limit: limit, stop: stop } }).unwrap();
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 0, held: 11 });
+
+    // Bob: Buy 10 @ 100 and Sell 5 @ 100, which triggers the stop leg
+    let bid = Order::new(6, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    let ask = Order::new(7, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 100, 5);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    assert_eq!(receipt.triggered.len(), 1);
+    assert_eq!(receipt.unlinked.len(), 1);
+    assert_eq!(receipt.unlinked[0].id, 4);
+
+    // What is left held is the rest of the first limit leg
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 0, held: 6 });
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks.len(), 1);
+}
+
+#[test]
+fn it_expires_both_legs_of_oco_orders_together() {
+    const ALICE_USER_ID: UserId = 1;
+    const BASE_ASSET_ID: AssetId = 1;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 15);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-oco-expiry.json").unwrap(), balances).unwrap();
+
+    // Alice: Sell 10 @ 120, or @ 80 once it trades at 90, both until 1000
+    let mut limit = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 120, 10);
+    limit.time_in_force = TimeInForce::GoodTillDate(1000);
+    let mut stop = Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 80, 10);
+    stop.stop_price = Some(90);
+    stop.time_in_force = TimeInForce::GoodTillDate(1000);
+    engine.submit(Message { sequence: 0, timestamp: 1, payload: MessagePayload::CreateOcoOrders { limit: limit, stop: stop } }).unwrap();
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 5, held: 10 });
+
+    let adjust = MessagePayload::AdjustBalance { user_id: ALICE_USER_ID, asset_id: BASE_ASSET_ID, change: 0 };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 1000, payload: adjust }).unwrap();
+
+    assert_eq!(receipt.expired.iter().map(|x| x.id).collect::<Vec<_>>(), vec![1, 2]);
+    assert!(receipt.unlinked.is_empty());
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 15, held: 0 });
+}
+
+#[test]
+fn it_uncrosses_call_auction_and_settles_at_clearing_price() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 100);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-auction.json").unwrap(), balances).unwrap();
+
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::StartAuction { market_id: MARKET_ID } }).unwrap();
+
+    // Alice: Buy 10 @ 110 and Bob: Sell 10 @ 90, which do not match yet
+    let bid = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 110, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    let ask = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 90, 10);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    assert_eq!(receipt.indicative, Some((90, 10)));
+    assert_eq!(engine.book(MARKET_ID).unwrap().asks.len(), 1);
+
+    // Orders that cannot rest are turned away
+    let market_buy = Order::market(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 0, Some(100));
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(market_buy) });
+    assert_eq!(outcome, Err(EngineError::AuctionInProgress(MARKET_ID)));
+
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::EndAuction { market_id: MARKET_ID } }).unwrap();
+
+    match receipt.report {
+        Report::AuctionEnded { trades, .. } => {
+            assert_eq!(trades.len(), 1);
+            assert_eq!(trades[0].price, 90);
+            assert_eq!(trades[0].size, 10);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    // Alice bid 110 but paid the clearing price
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10000 - 900, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 10, held: 0 });
+    assert_eq!(engine.balances.get(BOB_USER_ID, QUOTE_ASSET_ID), Balance { available: 900, held: 0 });
+
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::EndAuction { market_id: MARKET_ID } });
+    assert_eq!(outcome, Err(EngineError::NoAuctionInProgress(MARKET_ID)));
+}
+
+#[test]
+fn it_charges_taker_fees_and_prevents_self_trades_in_auction() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let mut config = Config::hardcoded();
+
+    {
+        let market = config.markets.get_mut(&MARKET_ID).unwrap();
+        market.maker_fee = 0;
+        market.taker_fee = 10000;
+    }
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 1000000);
+    balances.adjust_balance(ALICE_USER_ID, BASE_ASSET_ID, 1000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 1000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-auction-fees.json").unwrap(), balances).unwrap();
+
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::StartAuction { market_id: MARKET_ID } }).unwrap();
+
+    // Alice: Buy 1000 @ 110 and Sell 500 @ 100, Bob: Sell 500 @ 100
+    let mut bid = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 110, 1000);
+    bid.self_trade_prevention = Some(SelfTradePrevention::CancelNewest);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    let ask = Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 100, 500);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+    let ask = Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Sell, 100, 500);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    assert_eq!(receipt.indicative, Some((100, 1000)));
+
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::EndAuction { market_id: MARKET_ID } }).unwrap();
+
+    // Both sides pay the taker fee, and what Alice would have traded with
+    // herself is canceled on both of her orders
+    match receipt.report {
+        Report::AuctionEnded { trades, canceled, .. } => {
+            assert_eq!(trades.len(), 1);
+            assert_eq!((trades[0].maker_order_id, trades[0].taker_order_id, trades[0].size), (2, 1, 500));
+            assert_eq!(trades[0].taker_fee, 5);
+            assert_eq!(trades[0].maker_fee, 500);
+            assert_eq!(canceled.iter().map(|x| (x.id, x.remaining)).collect::<Vec<_>>(), vec![(1, 500), (3, 500)]);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert!(engine.book(MARKET_ID).unwrap().bids.is_empty());
+    assert!(engine.book(MARKET_ID).unwrap().asks.is_empty());
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 1000000 - 50000, held: 0 });
+    assert_eq!(engine.balances.get(ALICE_USER_ID, BASE_ASSET_ID), Balance { available: 1000 + 500 - 5, held: 0 });
+    assert_eq!(engine.balances.get(BOB_USER_ID, QUOTE_ASSET_ID), Balance { available: 50000 - 500, held: 0 });
+    assert_eq!(engine.balances.get(BOB_USER_ID, BASE_ASSET_ID), Balance { available: 500, held: 0 });
+}
+
+#[test]
+fn it_restricts_orders_by_market_status() {
+    const ALICE_USER_ID: UserId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 10000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-status.json").unwrap(), balances).unwrap();
+
+    let bid = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    let bid = Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::CancelOnly };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    // New orders are rejected but reductions and cancels are not
+    let bid = Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) });
+    assert_eq!(outcome, Err(EngineError::MarketNotOpen(MARKET_ID)));
+
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: None, size: Some(20) };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend });
+    assert_eq!(outcome, Err(EngineError::MarketNotOpen(MARKET_ID)));
+
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: None, size: Some(5) };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: amend }).unwrap();
+
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID) };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::Halted };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    // Only operators cancel in a halted market
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 2, user_id: Some(ALICE_USER_ID) };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel });
+    assert_eq!(outcome, Err(EngineError::MarketNotOpen(MARKET_ID)));
+
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 2, user_id: None };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::PostOnly };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    // Orders that would take are rejected
+    let market_buy = Order::market(4, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 0, Some(1000));
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(market_buy) });
+    assert_eq!(outcome, Err(EngineError::PostOnlyWouldTake));
+
+    let bid = Order::new(5, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+}
+
+#[test]
+fn it_enforces_tick_lot_and_notional_rules() {
+    const ALICE_USER_ID: UserId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let mut config = Config::hardcoded();
+    {
+        let market = config.markets.get_mut(&MARKET_ID).unwrap();
+        market.tick_size = 5;
+        market.lot_size = 10;
+        market.min_size = 20;
+        market.max_size = 1000;
+        market.min_notional = 5000;
+    }
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 1000000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-rules.json").unwrap(), balances).unwrap();
+
+    let mut submit = |order: Order| engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(order) }).map(|_| ());
+
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 102, 100)), Err(EngineError::PriceNotOnTick(102)));
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 105)), Err(EngineError::SizeNotOnLot(105)));
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10)), Err(EngineError::SizeBelowMinimum(10)));
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 2000)), Err(EngineError::SizeAboveMaximum(2000)));
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 40)), Err(EngineError::NotionalBelowMinimum(4000)));
+    assert_eq!(submit(Order::market(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 0, Some(100))), Err(EngineError::NotionalBelowMinimum(100)));
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 50)), Ok(()));
+}
+
+#[test]
+fn it_reduces_orders_that_would_be_rejected_as_new_orders() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let mut config = Config::hardcoded();
+    {
+        let market = config.markets.get_mut(&MARKET_ID).unwrap();
+        market.tick_size = 5;
+        market.lot_size = 10;
+        market.min_size = 20;
+        market.min_notional = 2000;
+        market.price_band = Some(PriceBand { rate: 1000, reference: PriceReference::LastTrade });
+    }
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 1000000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 1000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-reduce.json").unwrap(), balances).unwrap();
+
+    // Alice: Buy 50 @ 100
+    let bid = Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 50);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    // The price moves to 115, leaving the bid outside the band
+    for &(order_id, price) in [(2, 110), (4, 115)].iter() {
+        let ask = Order::new(order_id, BOB_USER_ID, MARKET_ID, OrderSide::Sell, price, 20);
+        engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+        let bid = Order::new(order_id + 1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, price, 20);
+        engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+    }
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().last_price(), Some(115));
+
+    let amend = |size: OrderSize| MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 1, user_id: Some(ALICE_USER_ID), price: None, size: Some(size) };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend(25) });
+    assert_eq!(outcome, Err(EngineError::SizeNotOnLot(25)));
+
+    // Below the minimum size and notional, and outside the band
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend(10) }).unwrap();
+
+    match receipt.report {
+        Report::OrderReduced(order) => assert_eq!(order.remaining, 10),
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 1000000 - 110 * 20 - 115 * 20 - 1000, held: 1000 });
+}
+
+#[test]
+fn it_refuses_markets_without_tick_size() {
+    let mut config = Config::hardcoded();
+    config.markets.get_mut(&1).unwrap().tick_size = 0;
+
+    let balances = Balances::new(config.clone());
+    let engine = SuezEngine::new(config, JsonJournalWriter::new("journal-tick.json").unwrap(), balances);
+    assert_eq!(engine.err(), Some(StartError::ZeroTickOrLotSize(1)));
+}
+
+#[test]
+fn it_bands_prices_and_halts_on_circuit_breaker() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let mut config = Config::hardcoded();
+    {
+        let market = config.markets.get_mut(&MARKET_ID).unwrap();
+        market.price_band = Some(PriceBand { rate: 1000, reference: PriceReference::LastTrade });
+        market.circuit_breaker = Some(CircuitBreaker { rate: 500, window: 60, action: CircuitBreakerAction::Halt });
+    }
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 1000000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 1000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-breaker.json").unwrap(), balances).unwrap();
+
+    let mut submit = |order: Order, timestamp: u64| engine.submit(Message { sequence: 0, timestamp: timestamp, payload: MessagePayload::CreateOrder(order) }).map(|receipt| receipt.tripped);
+
+    // Nothing to band against before the first trade
+    assert_eq!(submit(Order::new(1, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10), 1), Ok(None));
+    assert_eq!(submit(Order::new(2, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10), 1), Ok(None));
+
+    assert_eq!(submit(Order::new(3, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 89, 10), 1), Err(EngineError::PriceOutsideBand(89)));
+
+    // The first trade has left the window when the price moves
+    assert_eq!(submit(Order::new(4, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 106, 10), 100), Ok(None));
+    assert_eq!(submit(Order::new(5, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 106, 10), 100), Ok(None));
+
+    assert_eq!(submit(Order::new(6, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10), 110), Ok(None));
+    assert_eq!(submit(Order::new(7, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 100, 10), 110), Ok(Some(Report::MarketStatusSet {
+        market_id: MARKET_ID,
+        status: MarketStatus::Halted,
+    })));
+
+    assert_eq!(submit(Order::new(8, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10), 110), Err(EngineError::MarketNotOpen(MARKET_ID)));
+}
+
+#[test]
+fn it_stops_market_orders_at_the_price_band() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let mut config = Config::hardcoded();
+    config.markets.get_mut(&MARKET_ID).unwrap().price_band = Some(PriceBand { rate: 1000, reference: PriceReference::LastTrade });
+
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 100000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 1000);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-protection.json").unwrap(), balances).unwrap();
+
+    // Bob: Sell 10 @ 100, 10 @ 105 and 10 @ 150 before there is a price to
+    // band against, Alice: Buy 5 @ 100
+    for &(order_id, price) in [(1, 100), (2, 105), (3, 150)].iter() {
+        let ask = Order::new(order_id, BOB_USER_ID, MARKET_ID, OrderSide::Sell, price, 10);
+        engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+    }
+
+    let bid = Order::new(4, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 100, 5);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    // Alice: Buy 40 at market, which stops at 10% above the last price of 100
+    let bid = Order::market(5, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 40, None);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { order, trades, resting, .. } => {
+            assert_eq!(trades.iter().map(|x| (x.price, x.size)).collect::<Vec<_>>(), vec![(100, 5), (105, 10)]);
+            assert_eq!(order.remaining, 25);
+            assert!(!resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().best_ask(), Some(150));
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 100000 - 500 - 500 - 1050, held: 0 });
+}
+
+#[test]
+fn it_reprices_pegged_orders_and_their_holds() {
+    const ALICE_USER_ID: UserId = 1;
+    const BOB_USER_ID: UserId = 2;
+    const BASE_ASSET_ID: AssetId = 1;
+    const QUOTE_ASSET_ID: AssetId = 2;
+    const MARKET_ID: MarketId = 1;
+
+    let config = Config::hardcoded();
+    let mut balances = Balances::new(config.clone());
+    balances.adjust_balance(ALICE_USER_ID, QUOTE_ASSET_ID, 1060);
+    balances.adjust_balance(BOB_USER_ID, QUOTE_ASSET_ID, 10000);
+    balances.adjust_balance(BOB_USER_ID, BASE_ASSET_ID, 10);
+
+    let mut engine = SuezEngine::new(config, JsonJournalWriter::new("journal-peg.json").unwrap(), balances).unwrap();
+
+    let bid = Order::new(1, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 100, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    // Alice: Buy 10 pegged one above the best bid, up to 110
+    let mut pegged = Order::new(2, ALICE_USER_ID, MARKET_ID, OrderSide::Buy, 0, 10);
+    pegged.peg = Some(Peg { reference: PegReference::Primary, offset: 1, cap: Some(110) });
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(pegged) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { order, resting, .. } => {
+            assert_eq!(order.price, 101);
+            assert!(resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 50, held: 1010 });
+
+    // A better bid moves the peg up, holding more
+    let bid = Order::new(3, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 104, 10);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    assert_eq!(receipt.repriced.iter().map(|x| (x.id, x.price)).collect::<Vec<_>>(), vec![(2, 105)]);
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[0].id, 2);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 10, held: 1050 });
+
+    // Alice cannot afford to follow a bid at 108
+    let bid = Order::new(4, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 108, 10);
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    assert!(receipt.repriced.is_empty());
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[1].price, 105);
+
+    // Back down to one above the original bid, releasing the difference
+    for order_id in vec![4, 3] {
+        let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: order_id, user_id: Some(BOB_USER_ID) };
+        engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+    }
+
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[0].price, 101);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 50, held: 1010 });
+
+    // The book prices pegs, so their price cannot be amended
+    let amend = MessagePayload::AmendOrder { market_id: MARKET_ID, order_id: 2, user_id: Some(ALICE_USER_ID), price: Some(102), size: None };
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: amend });
+    assert_eq!(outcome, Err(EngineError::InvalidPeg(2)));
+
+    // Pegs stay put while the market is halted and catch up when it reopens
+    let bid = Order::new(6, BOB_USER_ID, MARKET_ID, OrderSide::Buy, 95, 10);
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(bid) }).unwrap();
+
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::Halted };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    let cancel = MessagePayload::CancelOrder { market_id: MARKET_ID, order_id: 1, user_id: None };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: cancel }).unwrap();
+
+    assert!(receipt.repriced.is_empty());
+    assert_eq!(engine.book(MARKET_ID).unwrap().bids[0].price, 101);
+
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::Open };
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    assert_eq!(receipt.repriced.iter().map(|x| (x.id, x.price)).collect::<Vec<_>>(), vec![(2, 96)]);
+    assert_eq!(engine.balances.get(ALICE_USER_ID, QUOTE_ASSET_ID), Balance { available: 100, held: 960 });
+
+    // Pegs never take, so they are accepted while the market is post only
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::PostOnly };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    // Bob: Sell 10 pegged five above the best bid
+    let mut ask = Order::new(7, BOB_USER_ID, MARKET_ID, OrderSide::Sell, 0, 10);
+    ask.peg = Some(Peg { reference: PegReference::Opposite, offset: 5, cap: None });
+    let receipt = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(ask) }).unwrap();
+
+    match receipt.report {
+        Report::OrderCreated { order, resting, .. } => {
+            assert_eq!(order.price, 100);
+            assert!(resting);
+        },
+        _ => panic!("incorrect report"),
+    }
+
+    let status = MessagePayload::SetMarketStatus { market_id: MARKET_ID, status: MarketStatus::Open };
+    engine.submit(Message { sequence: 0, timestamp: 0, payload: status }).unwrap();
+
+    // Pegs must rest
+    pegged.id = 5;
+    pegged.time_in_force = TimeInForce::ImmediateOrCancel;
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(pegged) });
+    assert_eq!(outcome, Err(EngineError::InvalidPeg(5)));
+
+    // Offsets that could overflow a price and caps that are not prices
+    pegged.time_in_force = TimeInForce::GoodTillCanceled;
+    pegged.peg = Some(Peg { reference: PegReference::Primary, offset: i64::min_value(), cap: None });
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(pegged) });
+    assert_eq!(outcome, Err(EngineError::InvalidPeg(5)));
+
+    pegged.peg = Some(Peg { reference: PegReference::Primary, offset: 1, cap: Some(0) });
+    let outcome = engine.submit(Message { sequence: 0, timestamp: 0, payload: MessagePayload::CreateOrder(pegged) });
+    assert_eq!(outcome, Err(EngineError::InvalidPeg(5)));
 }